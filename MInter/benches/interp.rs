@@ -0,0 +1,58 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-06 16:02:37
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-06 16:02:37
+ * @Description: Time the scripts in benches/mua with both engines
+ * @Copyright: Copyright (c) 2022
+ */
+
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use std::time::{Duration, Instant};
+
+const RUNS: u32 = 5;
+const ENGINES: [&str; 2] = ["tree", "vm"];
+
+fn main() {
+    let exe = env!("CARGO_BIN_EXE_MInter");
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("benches/mua");
+
+    let mut scripts: Vec<_> = fs::read_dir(&dir)
+        .expect("benches/mua not found")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mua"))
+        .collect();
+    scripts.sort();
+
+    for script in scripts {
+        for engine in ENGINES {
+            let mut best = Duration::MAX;
+            for _ in 0..RUNS {
+                let start = Instant::now();
+                let output = Command::new(exe)
+                    .arg(format!("--engine={}", engine))
+                    .arg(&script)
+                    .output()
+                    .unwrap();
+                let time = start.elapsed();
+                assert!(
+                    output.status.success(),
+                    "{} fails with {}:\n{}",
+                    script.display(),
+                    engine,
+                    String::from_utf8_lossy(&output.stderr)
+                );
+                best = best.min(time);
+            }
+            println!(
+                "{:<16} {:<4} {:>12.2?} (best of {})",
+                script.file_stem().unwrap().to_string_lossy(),
+                engine,
+                best,
+                RUNS
+            );
+        }
+    }
+}
@@ -6,47 +6,288 @@
  * @Description: file information
  * @Copyright: Copyright (c) 2021
  */
-use std::collections::VecDeque;
+use crate::parser::is_num;
+use std::fmt;
 use std::fs::File;
 use std::io::{self, BufRead, Read};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Pos {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub col: usize,
+}
+
+impl fmt::Display for Pos {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.col)
+    }
+}
+
+// What code is made of, words are told apart by how they are written
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Token {
+    Number(String),
+    // "word, without the quote
+    Word(String),
+    // :name, without the colon
+    Var(String),
+    LBracket,
+    RBracket,
+    // Anything else, names of operations, true and false among them
+    Name(String),
+    // From // to the end of the line, without the slashes
+    Comment(String),
+}
+
+impl Token {
+    // The token a word without brackets is
+    pub fn classify(word: &str) -> Token {
+        if let Some(word) = word.strip_prefix('"') {
+            Token::Word(word.to_owned())
+        } else if let Some(name) = word.strip_prefix(':') {
+            Token::Var(name.to_owned())
+        } else if is_num(word) && word.parse::<f64>().is_ok() {
+            Token::Number(word.to_owned())
+        } else {
+            Token::Name(word.to_owned())
+        }
+    }
+}
+
+// As written
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Number(s) | Token::Name(s) => write!(f, "{}", s),
+            Token::Word(s) => write!(f, "\"{}", s),
+            Token::Var(s) => write!(f, ":{}", s),
+            Token::LBracket => write!(f, "["),
+            Token::RBracket => write!(f, "]"),
+            Token::Comment(s) => write!(f, "//{}", s),
+        }
+    }
+}
+
+// A token with where it comes from, code rebuilt from lists has no position
+pub type Spanned = (Token, Option<Pos>);
 
 pub struct Input<'a> {
     _input: _Input<'a>,
-    buffer: VecDeque<String>,
+    // The line being read, and where in it
+    chars: Vec<char>,
+    col: usize,
+    // Tokens to replay, read before any line
+    replay: &'a [Spanned],
+    name: Option<Rc<str>>,
+    line: usize,
+    // Lists open, a word literal out of any takes brackets in
+    depth: usize,
 }
 
 impl<'a> Input<'a> {
-    pub fn console(stdin: &'a io::Stdin) -> Input<'a> {
+    fn new(input: _Input<'a>, name: Option<Rc<str>>) -> Input<'a> {
         Input {
-            _input: _Input::console(stdin),
-            buffer: VecDeque::new(),
+            _input: input,
+            chars: vec![],
+            col: 0,
+            replay: &[],
+            name,
+            line: 0,
+            depth: 0,
         }
     }
 
-    pub fn file(path: &str) -> Input<'a> {
-        Input {
-            _input: _Input::file(path).unwrap(),
-            buffer: VecDeque::new(),
-        }
+    pub fn console() -> Input<'a> {
+        Input::new(
+            _Input {
+                source: Box::new(StdinLines::default()),
+            },
+            Some("<stdin>".into()),
+        )
+    }
+
+    // Stdin for data read while running, it may share it with the console
+    pub fn stdin() -> Input<'static> {
+        Input::reader(StdinLines::default())
+    }
+
+    // Any source, e.g. a Cursor over text an embedder already has
+    pub fn reader(source: impl BufRead + 'a) -> Input<'a> {
+        Input::new(
+            _Input {
+                source: Box::new(source),
+            },
+            None,
+        )
+    }
+
+    // Like reader, with positions in errors given under name
+    pub fn named(source: impl BufRead + 'a, name: &str) -> Input<'a> {
+        Input::new(
+            _Input {
+                source: Box::new(source),
+            },
+            Some(name.into()),
+        )
+    }
+
+    pub fn file(path: &str) -> io::Result<Input<'a>> {
+        Ok(Input::new(_Input::file(path)?, Some(path.into())))
     }
 
     pub fn string(content: &'a str) -> Input<'a> {
-        Input {
-            _input: _Input::string(content),
-            buffer: VecDeque::new(),
+        Input::new(_Input::string(content), None)
+    }
+
+    // Replay tokens already read from somewhere else, e.g. a function body
+    pub fn tokens(tokens: &'a [Spanned]) -> Input<'a> {
+        let mut input = Input::new(_Input::string(""), None);
+        input.replay = tokens;
+        input
+    }
+
+    // A source which fails ends there, bytes which aren't utf8 are
+    // taken as U+FFFD
+    fn read_line(&mut self) -> Option<()> {
+        let mut bytes = vec![];
+        match self._input.read_until(b'\n', &mut bytes) {
+            Ok(0) | Err(_) => return None,
+            Ok(_) => (),
         }
+        self.line += 1;
+        self.chars = String::from_utf8_lossy(&bytes).chars().collect();
+        self.col = 0;
+        Some(())
     }
 
-    pub fn next_word(&mut self) -> Option<String> {
-        while self.buffer.is_empty() {
-            let mut str = String::new();
-            if self._input.read_line(&mut str).unwrap() == 0 {
-                return None;
+    fn skip_space(&mut self) {
+        while self.col < self.chars.len() && self.chars[self.col].is_whitespace() {
+            self.col += 1;
+        }
+    }
+
+    // Move to the next thing on this line or the lines after, false at
+    // the end of input
+    fn fill(&mut self) -> bool {
+        loop {
+            self.skip_space();
+            if self.col < self.chars.len() {
+                return true;
+            }
+            if self.read_line().is_none() {
+                return false;
             }
-            self.buffer = str.split_whitespace().map(|w| w.to_owned()).collect()
         }
+    }
 
-        self.buffer.pop_front()
+    // Up to the first whitespace, or bracket too if brackets end it
+    fn take_word(&mut self, brackets: bool) -> String {
+        let start = self.col;
+        while self.col < self.chars.len() {
+            let c = self.chars[self.col];
+            if c.is_whitespace() || (brackets && (c == '[' || c == ']')) {
+                break;
+            }
+            self.col += 1;
+        }
+        self.chars[start..self.col].iter().collect()
+    }
+
+    pub fn next_token(&mut self) -> Option<Spanned> {
+        if let Some((token, rest)) = self.replay.split_first() {
+            self.replay = rest;
+            return Some(token.clone());
+        }
+        if !self.fill() {
+            return None;
+        }
+
+        let pos = self.name.as_ref().map(|file| Pos {
+            file: Rc::clone(file),
+            line: self.line,
+            col: self.col + 1,
+        });
+        let token = match self.chars[self.col] {
+            // Only where a token starts, "http://x is a word
+            '/' if self.chars.get(self.col + 1) == Some(&'/') => {
+                let comment = self.chars[self.col + 2..].iter().collect::<String>();
+                self.col = self.chars.len();
+                Token::Comment(comment.trim_end().to_owned())
+            }
+            '[' => {
+                self.col += 1;
+                self.depth += 1;
+                Token::LBracket
+            }
+            ']' => {
+                self.col += 1;
+                self.depth = self.depth.saturating_sub(1);
+                Token::RBracket
+            }
+            // Brackets are part of a word literal, lists end a word in them
+            c => {
+                let quoted = c == '"' && self.depth == 0;
+                Token::classify(&self.take_word(!quoted))
+            }
+        };
+        Some((token, pos))
+    }
+
+    // Data is taken as words between whitespace, brackets and all
+    pub fn next_word(&mut self) -> Option<String> {
+        if !self.fill() {
+            return None;
+        }
+        Some(self.take_word(false))
+    }
+
+    pub fn peek_word(&mut self) -> Option<String> {
+        if !self.fill() {
+            return None;
+        }
+        let col = self.col;
+        let word = self.take_word(false);
+        self.col = col;
+        Some(word)
+    }
+
+    // Tokens left, only meaningful for replayed tokens
+    pub fn remaining(&self) -> usize {
+        self.replay.len()
+    }
+
+    // Whether only blanks or a comment are left on the current line, so
+    // what comes next is on a line not read yet
+    pub fn at_line_end(&self) -> bool {
+        let rest: String = self.chars[self.col..].iter().collect();
+        let rest = rest.trim_start();
+        self.replay.is_empty() && (rest.is_empty() || rest.starts_with("//"))
+    }
+
+    // Drop what's left on the current line
+    pub fn clear(&mut self) {
+        self.chars.clear();
+        self.col = 0;
+        self.replay = &[];
+        self.depth = 0;
+    }
+
+    // Take the rest of the current line, or a fresh line if nothing is pending
+    pub fn next_line(&mut self) -> Option<Vec<String>> {
+        self.skip_space();
+        if self.col >= self.chars.len() {
+            self.read_line()?;
+        }
+        let mut words = vec![];
+        loop {
+            self.skip_space();
+            if self.col >= self.chars.len() {
+                return Some(words);
+            }
+            words.push(self.take_word(false));
+        }
     }
 }
 
@@ -55,12 +296,6 @@ struct _Input<'a> {
 }
 
 impl<'a> _Input<'a> {
-    pub fn console(stdin: &'a io::Stdin) -> _Input<'a> {
-        _Input {
-            source: Box::new(stdin.lock()),
-        }
-    }
-
     pub fn file(path: &str) -> io::Result<_Input<'a>> {
         File::open(path).map(|file| _Input {
             source: Box::new(io::BufReader::new(file)),
@@ -69,7 +304,7 @@ impl<'a> _Input<'a> {
 
     pub fn string(content: &'a str) -> _Input<'a> {
         _Input {
-            source: Box::new(content.clone().as_bytes()),
+            source: Box::new(content.as_bytes()),
         }
     }
 }
@@ -88,4 +323,35 @@ impl<'a> BufRead for _Input<'a> {
     fn consume(&mut self, amt: usize) {
         self.source.consume(amt);
     }
-}
\ No newline at end of file
+}
+
+// Stdin locked for a line at a time, so the console and read can take
+// turns at it. Nothing is read ahead of the line asked for
+#[derive(Default)]
+struct StdinLines {
+    line: Vec<u8>,
+    at: usize,
+}
+
+impl Read for StdinLines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for StdinLines {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.at == self.line.len() {
+            self.line.clear();
+            self.at = 0;
+            io::stdin().lock().read_until(b'\n', &mut self.line)?;
+        }
+        Ok(&self.line[self.at..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.at += amt;
+    }
+}
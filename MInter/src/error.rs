@@ -0,0 +1,106 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-02 15:20:11
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-02 15:20:11
+ * @Description: file information
+ * @Copyright: Copyright (c) 2022
+ */
+
+use crate::cmdin::Pos;
+use crate::interp::Frame;
+use std::fmt;
+use std::io;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    UndefinedName,
+    TypeMismatch,
+    ArityError,
+    DivideByZero,
+    IoError,
+    ParseError,
+    RecursionLimit,
+    StepLimit,
+    SizeLimit,
+    AccessDenied,
+    HostError,
+    Interrupted,
+    // A break or continue out of any loop
+    FlowError,
+    // A value of the right type an operation still can't take
+    ArgumentError,
+    // Names the embedding program gives which can't be made
+    InvalidName,
+    // The program ran exit, it's not a failure
+    Exit,
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ErrorKind::UndefinedName => "Undefined Name",
+            ErrorKind::TypeMismatch => "Type Mismatch",
+            ErrorKind::ArityError => "Arity Error",
+            ErrorKind::DivideByZero => "Divide By Zero",
+            ErrorKind::IoError => "IO Error",
+            ErrorKind::ParseError => "Parse Error",
+            ErrorKind::RecursionLimit => "Recursion Limit",
+            ErrorKind::StepLimit => "Step Limit",
+            ErrorKind::SizeLimit => "Size Limit",
+            ErrorKind::AccessDenied => "Access Denied",
+            ErrorKind::HostError => "Host Error",
+            ErrorKind::Interrupted => "Interrupted",
+            ErrorKind::FlowError => "Flow Error",
+            ErrorKind::ArgumentError => "Argument Error",
+            ErrorKind::InvalidName => "Invalid Name",
+            ErrorKind::Exit => "Exit",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MuaError {
+    pub kind: ErrorKind,
+    pub msg: String,
+    pub pos: Option<Pos>,
+    // Calls on the way to the error, innermost first
+    pub backtrace: Vec<Frame>,
+}
+
+impl MuaError {
+    pub fn new(kind: ErrorKind, msg: impl Into<String>) -> Self {
+        MuaError {
+            kind,
+            msg: msg.into(),
+            pos: None,
+            backtrace: vec![],
+        }
+    }
+
+    // Keep the innermost position, outer ones are only a fallback
+    pub fn at(mut self, pos: &Option<Pos>) -> Self {
+        if self.pos.is_none() {
+            self.pos = pos.clone();
+        }
+        self
+    }
+}
+
+impl fmt::Display for MuaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.pos {
+            Some(pos) => write!(f, "{}: {}", pos, self.msg),
+            None => write!(f, "{}", self.msg),
+        }
+    }
+}
+
+impl std::error::Error for MuaError {}
+
+impl From<io::Error> for MuaError {
+    fn from(err: io::Error) -> Self {
+        MuaError::new(ErrorKind::IoError, err.to_string())
+    }
+}
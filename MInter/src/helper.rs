@@ -30,4 +30,31 @@ macro_rules! vecdeque {
     ($($x:expr),*) => (
         VecDeque::from([$($x), *])
     )
-}
\ No newline at end of file
+}
+
+// Operations of an expression which differ only in what they compute,
+// each with the keyword it's written as
+#[macro_export]
+macro_rules! operators {
+    ( $name:ident { $( $op:ident = $word:literal ),* } ) => {
+        #[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+        pub enum $name {
+            $( $op ),*
+        }
+
+        impl $name {
+            pub fn parse(word: &str) -> Option<Self> {
+                match word {
+                    $( $word => Some($name::$op), )*
+                    _ => None,
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $( $name::$op => $word ),*
+                }
+            }
+        }
+    };
+}
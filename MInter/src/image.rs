@@ -0,0 +1,283 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-14 10:05:31
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-14 10:05:31
+ * @Description: Binary images of the global names, for saveimage
+ * @Copyright: Copyright (c) 2022
+ */
+
+use crate::cmdin::Input;
+use crate::error::{ErrorKind, MuaError};
+use crate::parser::Block;
+use crate::syntax::{Context, ListType, SymTable, ValType};
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::rc::Rc;
+
+// An image is MAGIC, VERSION, then the global names:
+//   table   := count (name value)*
+//   value   := 0 f64 | 1 str | 2 u8 | 3 count value* | 4 context count value*
+//              count str* count value* str | 5
+//   context := 0 | 1 context table | 2 index
+// A context is written in full where it's first met, and by its index
+// after, so closures sharing one still do. A function's body is also
+// kept as its code's text, comments and all. Numbers are little endian,
+// counts are u64 and strings a count of utf8 bytes
+const MAGIC: &[u8; 8] = b"MUAIMAGE";
+pub const VERSION: u32 = 2;
+
+// Lists and contexts nest no deeper when read, a bad image would take
+// the stack otherwise
+const MAX_NESTING: usize = 1000;
+
+pub fn write(global: &SymTable) -> Vec<u8> {
+    let mut writer = Writer {
+        bytes: MAGIC.to_vec(),
+        contexts: HashMap::new(),
+    };
+    writer.bytes.extend(VERSION.to_le_bytes());
+    writer.table(global);
+    writer.bytes
+}
+
+// The names of global become those in the image. It's all read before,
+// global is left as is if the image is bad
+pub fn read(bytes: &[u8], global: &Rc<RefCell<SymTable>>) -> Result<(), MuaError> {
+    let mut reader = Reader {
+        bytes,
+        at: 0,
+        depth: 0,
+        contexts: vec![],
+        global: Rc::clone(global),
+    };
+    if reader.take(MAGIC.len())? != MAGIC {
+        return corrupt("not an image");
+    }
+    let version = u32::from_le_bytes(reader.take(4)?.try_into().unwrap());
+    if version != VERSION {
+        return corrupt(format!("image version {} is not supported", version));
+    }
+    let vars = reader.table()?;
+    if reader.at != bytes.len() {
+        return corrupt("image has trailing bytes");
+    }
+
+    let mut global = global.borrow_mut();
+    global.clear_all();
+    for (name, val) in vars {
+        global.bind(name, val);
+    }
+    Ok(())
+}
+
+fn corrupt<T>(msg: impl Into<String>) -> Result<T, MuaError> {
+    Err(MuaError::new(ErrorKind::IoError, msg))
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+    contexts: HashMap<*const RefCell<SymTable>, u64>,
+}
+
+impl Writer {
+    fn count(&mut self, n: usize) {
+        self.bytes.extend((n as u64).to_le_bytes());
+    }
+
+    fn str(&mut self, s: &str) {
+        self.count(s.len());
+        self.bytes.extend(s.as_bytes());
+    }
+
+    fn table(&mut self, table: &SymTable) {
+        let mut vars: Vec<_> = table.get_keys_values().collect();
+        vars.sort_by_key(|(name, _)| *name);
+        self.count(vars.len());
+        for (name, val) in vars {
+            self.str(name);
+            self.value(val);
+        }
+    }
+
+    fn value(&mut self, val: &ValType) {
+        match val {
+            ValType::Num(n) => {
+                self.bytes.push(0);
+                self.bytes.extend(n.0.to_le_bytes());
+            }
+            ValType::Str(s) => {
+                self.bytes.push(1);
+                self.str(s);
+            }
+            ValType::Boolean(b) => self.bytes.extend([2, *b as u8]),
+            ValType::List(list, ListType::Ordinary) => {
+                self.bytes.push(3);
+                self.list(list);
+            }
+            ValType::List(list, ListType::Function(context, params, body, code)) => {
+                self.bytes.push(4);
+                self.context(context.as_ref().map(|context| &context.0));
+                self.list(list);
+                self.count(params.len());
+                params.iter().for_each(|param| self.str(param));
+                self.list(body);
+                self.str(&code.text());
+            }
+            ValType::Null | ValType::Retv(_) | ValType::Tail(_) | ValType::Leave(_) => {
+                self.bytes.push(5)
+            }
+        }
+    }
+
+    fn list(&mut self, list: &VecDeque<ValType>) {
+        self.count(list.len());
+        list.iter().for_each(|val| self.value(val));
+    }
+
+    fn context(&mut self, context: Option<&Rc<RefCell<SymTable>>>) {
+        let context = match context {
+            Some(context) => context,
+            None => return self.bytes.push(0),
+        };
+        if let Some(&index) = self.contexts.get(&Rc::as_ptr(context)) {
+            self.bytes.push(2);
+            self.bytes.extend(index.to_le_bytes());
+            return;
+        }
+        // Numbered first, a closure in its outer tables may be made in it
+        let index = self.contexts.len() as u64;
+        self.contexts.insert(Rc::as_ptr(context), index);
+        self.bytes.push(1);
+        self.context(context.borrow().get_context().as_ref());
+        self.table(&context.borrow());
+    }
+}
+
+struct Reader<'b> {
+    bytes: &'b [u8],
+    at: usize,
+    // Of the lists and contexts being read
+    depth: usize,
+    contexts: Vec<Rc<RefCell<SymTable>>>,
+    global: Rc<RefCell<SymTable>>,
+}
+
+impl<'b> Reader<'b> {
+    fn take(&mut self, n: usize) -> Result<&'b [u8], MuaError> {
+        if self.bytes.len() - self.at < n {
+            return corrupt("image is cut short");
+        }
+        self.at += n;
+        Ok(&self.bytes[self.at - n..self.at])
+    }
+
+    fn byte(&mut self) -> Result<u8, MuaError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn count(&mut self) -> Result<usize, MuaError> {
+        let n = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+        n.try_into().or_else(|_| corrupt("image is cut short"))
+    }
+
+    fn str(&mut self) -> Result<String, MuaError> {
+        let n = self.count()?;
+        String::from_utf8(self.take(n)?.to_vec()).or_else(|_| corrupt("image has a bad word"))
+    }
+
+    fn table(&mut self) -> Result<Vec<(String, ValType)>, MuaError> {
+        let n = self.count()?;
+        let mut vars = vec![];
+        for _ in 0..n {
+            vars.push((self.str()?, self.value()?));
+        }
+        Ok(vars)
+    }
+
+    fn nested<T>(
+        &mut self,
+        read: impl FnOnce(&mut Self) -> Result<T, MuaError>,
+    ) -> Result<T, MuaError> {
+        if self.depth == MAX_NESTING {
+            return corrupt("image nests too deep");
+        }
+        self.depth += 1;
+        let res = read(self);
+        self.depth -= 1;
+        res
+    }
+
+    fn value(&mut self) -> Result<ValType, MuaError> {
+        self.nested(Self::value_in)
+    }
+
+    fn value_in(&mut self) -> Result<ValType, MuaError> {
+        Ok(match self.byte()? {
+            0 => {
+                let n = f64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                ValType::Num(n.into())
+            }
+            1 => ValType::Str(self.str()?),
+            2 => ValType::Boolean(self.byte()? != 0),
+            3 => ValType::List(self.list()?.into(), ListType::Ordinary),
+            4 => {
+                let context = self.context()?.map(Context);
+                let list = self.list()?;
+                let mut params = vec![];
+                for _ in 0..self.count()? {
+                    params.push(self.str()?);
+                }
+                let body = self.list()?;
+                let text = self.str()?;
+                let mut input = Input::string(&text);
+                let tokens = std::iter::from_fn(|| input.next_token()).collect();
+                let code = Rc::new(Block::new(tokens).with_params(&params));
+                ValType::List(list.into(), ListType::Function(context, params, body, code))
+            }
+            5 => ValType::Null,
+            tag => return corrupt(format!("image has a bad value tag {}", tag)),
+        })
+    }
+
+    fn list(&mut self) -> Result<VecDeque<ValType>, MuaError> {
+        let n = self.count()?;
+        let mut list = VecDeque::new();
+        for _ in 0..n {
+            list.push_back(self.value()?);
+        }
+        Ok(list)
+    }
+
+    fn context(&mut self) -> Result<Option<Rc<RefCell<SymTable>>>, MuaError> {
+        self.nested(Self::context_in)
+    }
+
+    fn context_in(&mut self) -> Result<Option<Rc<RefCell<SymTable>>>, MuaError> {
+        match self.byte()? {
+            0 => Ok(None),
+            1 => {
+                let context = Rc::new(RefCell::new(SymTable::new(
+                    Some(Rc::clone(&self.global)),
+                    None,
+                )));
+                self.contexts.push(Rc::clone(&context));
+                let outer = self.context()?;
+                context.borrow_mut().set_context(outer);
+                for (name, val) in self.table()? {
+                    context.borrow_mut().bind(name, val);
+                }
+                Ok(Some(context))
+            }
+            2 => {
+                let index = u64::from_le_bytes(self.take(8)?.try_into().unwrap());
+                match self.contexts.get(index as usize) {
+                    Some(context) => Ok(Some(Rc::clone(context))),
+                    None => corrupt("image has a bad context"),
+                }
+            }
+            tag => corrupt(format!("image has a bad context tag {}", tag)),
+        }
+    }
+}
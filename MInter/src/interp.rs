@@ -9,325 +9,498 @@
 
 pub use crate::syntax::*;
 
-use crate::{vecdeque, Input};
-use ansi_term::Color;
-use ordered_float::OrderedFloat;
+use crate::cmdin::Pos;
+use crate::error::MuaError;
+use crate::output::{Output, Stdio};
+use crate::parser::Block;
+use crate::prim::{self, num, Callee};
+use crate::Input;
 use std::cell::RefCell;
-use std::collections::{HashSet, VecDeque};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::process::exit;
+use std::fmt;
+use std::path::PathBuf;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
-pub fn interpretor(input: &mut Input, env: Rc<RefCell<SymTable>>) -> ValType {
+// A user function being called
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub name: String,
+    pub args: Vec<ValType>,
+    pub pos: Option<Pos>,
+}
+
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)?;
+        for arg in &self.args {
+            write!(f, " {}", arg.to_origin())?;
+        }
+        if let Some(pos) = &self.pos {
+            write!(f, " at {}", pos)?;
+        }
+        Ok(())
+    }
+}
+
+// What a run may use, so untrusted code can't hang or eat the machine.
+// None is no limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    // Expressions evaluated by the tree engine, ops run by the vm
+    pub steps: Option<u64>,
+    // Calls deeper than this are an error. The tree engine nests them on
+    // the native stack, running out of it first is the same error
+    pub depth: usize,
+    // Of any value built, lists count their elements and words their bytes
+    pub size: Option<usize>,
+}
+
+pub const MAX_DEPTH: usize = 10000;
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            steps: None,
+            depth: MAX_DEPTH,
+            size: None,
+        }
+    }
+}
+
+// Which files save and load may touch
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Files {
+    #[default]
+    Any,
+    Denied,
+    // Only files under the directory, named relative to it
+    Within(PathBuf),
+}
+
+// States kept across the whole run
+pub struct Runtime {
+    pub stack: Vec<Frame>,
+    pub limits: Limits,
+    // Steps taken against limits.steps
+    pub steps: u64,
+    // Set from anywhere to stop the run at its next step
+    pub interrupt: Arc<AtomicBool>,
+    // State of the generator random draws from
+    pub random: u64,
+    pub files: Files,
+    // Relative file names are from here, the running script's directory
+    pub dir: Option<PathBuf>,
+    pub output: Box<dyn Output>,
+    // What read and readlist take words from, apart from the code
+    pub data: Input<'static>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime {
+            stack: vec![],
+            limits: Limits::default(),
+            steps: 0,
+            interrupt: Arc::default(),
+            random: prim::seed_state(prim::clock_seed()),
+            files: Files::default(),
+            dir: None,
+            output: Box::new(Stdio::default()),
+            data: Input::stdin(),
+        }
+    }
+}
+
+impl fmt::Debug for Runtime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Runtime")
+            .field("stack", &self.stack)
+            .field("limits", &self.limits)
+            .field("steps", &self.steps)
+            .field("files", &self.files)
+            .field("dir", &self.dir)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Runtime {
+    pub fn new() -> Self {
+        Runtime::default()
+    }
+}
+
+pub fn interpretor(
+    rt: &mut Runtime,
+    input: &mut Input,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
     use crate::parser::parse;
 
     let mut res = ValType::Null;
-    while let Some(expr) = parse(input, Rc::clone(&env)) {
-        res = interp_exp(input, expr, Rc::clone(&env));
+    while let Some(expr) = parse(input, Rc::clone(&env))? {
+        res = interp_exp(rt, &expr, Rc::clone(&env))?;
         if res.is_ret_value() {
-            return res.get_ret_value();
+            return prim::outside_loop(res).map(ValType::get_ret_value);
         }
     }
-    res
+    Ok(res)
 }
 
-fn interp_error(content: &str) -> ValType {
-    println!("{} - {}", Color::Red.paint("Interpret Error"), content);
-    ValType::Null
+// Run the statements of a block, until the end or a return.
+// A return leaves the block as it is, it's the function's to take
+pub fn run_block(
+    rt: &mut Runtime,
+    block: &Block,
+    env: Rc<RefCell<SymTable>>,
+    tail: bool,
+) -> Result<ValType, MuaError> {
+    let mut res = ValType::Null;
+    let mut start = 0;
+    while start < block.len() {
+        let stmt = match block.statement(start, &env)? {
+            Some(stmt) => stmt,
+            None => break,
+        };
+        res = if tail {
+            run_tail(rt, &stmt.expr, Rc::clone(&env))?
+        } else {
+            interp_exp(rt, &stmt.expr, Rc::clone(&env))?
+        };
+        if res.is_ret_value() {
+            return Ok(res);
+        }
+        start = stmt.end;
+    }
+    Ok(res)
 }
 
-pub fn interp_exp(input: &mut Input, expr: Expr, env: Rc<RefCell<SymTable>>) -> ValType {
-    use crate::parser::is_num;
+// Run a list literal kept as code, or a list computed at runtime
+fn run_list(
+    rt: &mut Runtime,
+    expr: &Expr,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    let res = if let Code(block) = expr {
+        run_block(rt, block, env, false)?
+    } else {
+        let block = prim::code(interp_exp(rt, expr, Rc::clone(&env))?, "run")?;
+        run_block(rt, &block, env, false)?
+    };
+    Ok(prim::leave_list(rt, res))
+}
 
+// The list a loop runs each round, parsed once for all of them
+fn loop_list(
+    rt: &mut Runtime,
+    expr: &Expr,
+    env: &Rc<RefCell<SymTable>>,
+    op: &str,
+) -> Result<Rc<Block>, MuaError> {
     match expr {
-        Value(mut val) => {
-            if let ValType::List(_, ListType::Function(closenv, _, body)) = &mut val {
-                // println!("Debug - It's Func!\n body: {}\n", vec2str(body));
-                let mut set = HashSet::new();
-                body.iter().map(|v| v.find_val_in_list(&mut set)).count();
-                // println!("Debug - set content: {:?}", set);
-                set.iter()
-                    .filter(|v| env.borrow().exist_local(v))
-                    .map(|v| {
-                        closenv.push(ClosureEnv {
-                            name: v.to_string(),
-                            val: env.borrow().lookup_local(v).unwrap(),
-                        })
-                    })
-                    .count();
-                // println!("Debug - closenv: {:?}", closenv);
-            }
-            val
-        }
-        Var(x) => env.borrow().lookup(&x).unwrap_or(ValType::Null),
-        Make(box x, box e) => {
-            if let ValType::Str(x) = interp_exp(input, x, Rc::clone(&env)) {
-                let val = interp_exp(input, e, Rc::clone(&env));
-                // println!("Debug - {:?}", val);
+        Code(block) => Ok(Rc::clone(block)),
+        _ => Ok(Rc::new(prim::code(
+            interp_exp(rt, expr, Rc::clone(env))?,
+            op,
+        )?)),
+    }
+}
 
-                env.borrow_mut().bind(x, val.clone());
-                val
-            } else {
-                interp_error("Make error, variable not a literal")
-            }
+// A statement which is the last thing its function does. There
+// `return f ...` is handed back to the caller, so it takes no stack
+fn run_tail(
+    rt: &mut Runtime,
+    expr: &Expr,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    match expr {
+        At(pos, inner) if !matches!(**inner, Function(..)) => {
+            prim::tick(rt).map_err(|e| e.at(pos))?;
+            run_tail(rt, inner, env).map_err(|e| e.at(pos))
         }
-        Erase(box n) => {
-            if let Value(ValType::Str(n)) = n {
-                env.borrow_mut().unbind(n);
-                ValType::Num(0.0.into())
-            } else {
-                interp_error("Erase error, variable not a literal")
+        Return(box At(pos, box Function(op, exprs))) => {
+            prim::tick(rt).map_err(|e| e.at(pos))?;
+            let (callee, args) = prepare_call(rt, op, exprs, &env).map_err(|e| e.at(pos))?;
+            match callee {
+                Callee::Host(host) => {
+                    Ok(Retv(Box::new((host.func)(&args).map_err(|e| e.at(pos))?)))
+                }
+                Callee::Mua(func) => Ok(Tail(Box::new(TailCall {
+                    name: op.clone(),
+                    func,
+                    args,
+                    pos: pos.clone(),
+                }))),
             }
         }
-        Print(box data) => {
-            let val = interp_exp(input, data, Rc::clone(&env));
-            println!("{}", val);
-            val
-        }
-        Thing(box data) => {
-            if let ValType::Str(v) = interp_exp(input, data, Rc::clone(&env)) {
-                env.borrow().lookup(&v).unwrap_or(ValType::Null)
+        If(b, r1, r2) => {
+            prim::tick(rt)?;
+            let branch = if prim::condition(interp_exp(rt, b, Rc::clone(&env))?, "if")? {
+                r1
             } else {
-                interp_error("Thing error, illegal variable")
+                r2
+            };
+            match &**branch {
+                Code(block) => run_block(rt, block, env, true),
+                _ => run_list(rt, branch, env),
             }
         }
-        Run(box cmd) => {
-            if let ValType::List(list, _) = interp_exp(input, cmd, Rc::clone(&env)) {
-                let content = vec2str(&list);
-                let mut input = Input::string(&content[1..content.len() - 1]);
+        Run(box Code(block)) => {
+            prim::tick(rt)?;
+            run_block(rt, block, env, true)
+        }
+        _ => interp_exp(rt, expr, env),
+    }
+}
 
-                interpretor(&mut input, Rc::clone(&env))
-            } else {
-                interp_error("Run error, illegal cmd list")
-            }
+pub fn interp_exp(
+    rt: &mut Runtime,
+    expr: &Expr,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    prim::tick(rt)?;
+    prim::check_stack()?;
+    let res = match expr {
+        Value(val) => prim::value(val, &env),
+        Var(x) => prim::thing(x, &env)?,
+        Make(x, e) => {
+            let x = prim::name(interp_exp(rt, x, Rc::clone(&env))?, "make")?;
+            let val = interp_exp(rt, e, Rc::clone(&env))?;
+            prim::make(x, val, &env)
         }
-        Judge(op, box value) => {
-            let val = interp_exp(input, value, Rc::clone(&env));
-            match op.as_str() {
-                "isname" => ValType::Boolean(env.borrow().exist_local(&val.to_string())),
-                "isnumber" => ValType::Boolean(is_num(val.to_string().as_str())),
-                "isword" => ValType::Boolean(val.is_string()),
-                "islist" => ValType::Boolean(val.is_list()),
-                "isbool" => ValType::Boolean(val.is_bool()),
-                "isempty" => ValType::Boolean(
-                    val.to_string()
-                        .trim_matches(|c| c == '[' || c == ']')
-                        .is_empty(),
-                ),
-                _ => interp_error("Judge error, illegal operator"),
-            }
+        Erase(n) => prim::erase(n.word(), &env)?,
+        Print(data) => {
+            let val = interp_exp(rt, data, Rc::clone(&env))?;
+            prim::print(rt, val)?
         }
-        Index(op, box value) => {
-            let list = interp_exp(input, value, Rc::clone(&env));
-            if let ValType::List(mut list, _) = list {
-                match op.as_str() {
-                    "first" => list.pop_front().unwrap(),
-                    "last" => list.pop_back().unwrap(),
-                    "butfirst" => {
-                        list.pop_front();
-                        ValType::List(list, ListType::Ordinary)
-                    }
-                    "butlast" => {
-                        list.pop_back();
-                        ValType::List(list, ListType::Ordinary)
-                    }
-                    _ => interp_error("Index error, illegal operator"),
-                }
-            } else {
-                let str = list.to_string();
-                match op.as_str() {
-                    "first" => ValType::Str(str.chars().next().unwrap().to_string()),
-                    "last" => ValType::Str(str.chars().last().unwrap().to_string()),
-                    "butfirst" => ValType::Str(str[1..].to_owned()),
-                    "butlast" => ValType::Str(str[0..str.len() - 1].to_owned()),
-                    _ => interp_error("Index error, illegal operator"),
-                }
-            }
+        Thing(data) => {
+            let name = prim::name(interp_exp(rt, data, Rc::clone(&env))?, "thing")?;
+            prim::thing(&name, &env)?
         }
-        Calc(op, box n1, box n2) => {
-            let v1: OrderedFloat<f64> = interp_exp(input, n1, Rc::clone(&env)).into();
-            let v2: OrderedFloat<f64> = interp_exp(input, n2, Rc::clone(&env)).into();
-            match op.as_str() {
-                "add" => ValType::Num(v1 + v2),
-                "sub" => ValType::Num(v1 - v2),
-                "mul" => ValType::Num(v1 * v2),
-                "div" => ValType::Num(v1 / v2),
-                "mod" => ValType::Num(v1 % v2),
-                _ => interp_error("Calc error, illegal operator"),
-            }
+        Run(cmd) => run_list(rt, cmd, Rc::clone(&env))?,
+        Judge(op, value) => {
+            let val = interp_exp(rt, value, Rc::clone(&env))?;
+            prim::judge(*op, val, &env)
         }
-        Comp(op, box n1, box n2) => {
-            let v1 = interp_exp(input, n1, Rc::clone(&env));
-            let v2 = interp_exp(input, n2, Rc::clone(&env));
-            if v1.is_num() && v2.is_num() {
-                // Integer compare
-                let v1: OrderedFloat<f64> = v1.into();
-                let v2: OrderedFloat<f64> = v2.into();
-                match op.as_str() {
-                    "eq" => ValType::Boolean(v1 == v2),
-                    "gt" => ValType::Boolean(v1 > v2),
-                    "lt" => ValType::Boolean(v1 < v2),
-                    _ => interp_error("Comp error, illegal operator"),
-                }
+        Index(op, value) => prim::index(*op, interp_exp(rt, value, Rc::clone(&env))?)?,
+        Math(op, n) => {
+            let v = num(interp_exp(rt, n, Rc::clone(&env))?)?;
+            prim::math(rt, *op, v)
+        }
+        Calc(op, n1, n2) => {
+            let v1 = num(interp_exp(rt, n1, Rc::clone(&env))?)?;
+            let v2 = num(interp_exp(rt, n2, Rc::clone(&env))?)?;
+            prim::calc(*op, v1, v2)?
+        }
+        Comp(op, n1, n2) => {
+            let v1 = interp_exp(rt, n1, Rc::clone(&env))?;
+            let v2 = interp_exp(rt, n2, Rc::clone(&env))?;
+            prim::comp(*op, v1, v2)?
+        }
+        Logic(op, n1, n2) => {
+            let v1 = interp_exp(rt, n1, Rc::clone(&env))?;
+            let v2 = interp_exp(rt, n2, Rc::clone(&env))?;
+            prim::logic(*op, v1, v2)?
+        }
+        Extend(op, l1, l2) => {
+            let v1 = interp_exp(rt, l1, Rc::clone(&env))?;
+            let v2 = interp_exp(rt, l2, Rc::clone(&env))?;
+            prim::extend(rt, *op, v1, v2)?
+        }
+        If(b, r1, r2) => {
+            if prim::condition(interp_exp(rt, b, Rc::clone(&env))?, "if")? {
+                run_list(rt, r1, env)?
             } else {
-                // String compare
-                let v1 = v1.to_string();
-                let v2 = v2.to_string();
-                match op.as_str() {
-                    "eq" => ValType::Boolean(v1 == v2),
-                    "gt" => ValType::Boolean(v1 > v2),
-                    "lt" => ValType::Boolean(v1 < v2),
-                    _ => interp_error("Comp error, illegal operator"),
-                }
+                run_list(rt, r2, env)?
             }
         }
-        Logic(op, box n1, box n2) => {
-            if let (ValType::Boolean(b1), ValType::Boolean(b2)) = (
-                interp_exp(input, n1, Rc::clone(&env)),
-                interp_exp(input, n2, Rc::clone(&env)),
-            ) {
-                match op.as_str() {
-                    "and" => ValType::Boolean(b1 && b2),
-                    "or" => ValType::Boolean(b1 || b2),
-                    "not" => ValType::Boolean(!b1),
-                    _ => interp_error("Logic error, illegal operator"),
+        Repeat(n, body) => {
+            let times = prim::times(interp_exp(rt, n, Rc::clone(&env))?)?;
+            let body = loop_list(rt, body, &env, "repeat")?;
+            let mut res = Null;
+            for _ in 0..times {
+                prim::tick(rt)?;
+                if let Some(exit) = prim::loop_exit(run_block(rt, &body, Rc::clone(&env), false)?) {
+                    res = exit;
+                    break;
                 }
-            } else {
-                interp_error("Logic error, not boolean input")
             }
+            res
         }
-        Extend(op, box l1, box l2) => {
-            let v1 = interp_exp(input, l1, Rc::clone(&env));
-            let v2 = interp_exp(input, l2, Rc::clone(&env));
-            match op.as_str() {
-                "sentence" => {
-                    let mut list = v1.to_list();
-                    list.extend(v2.to_list());
-                    ValType::List(list, ListType::Ordinary)
-                }
-                "list" => ValType::List(vecdeque![v1, v2], ListType::Ordinary),
-                "join" => {
-                    let mut list = v1.to_list();
-                    list.push_back(v2);
-                    ValType::List(list, ListType::Ordinary)
+        While(cond, body) => {
+            let cond = loop_list(rt, cond, &env, "while")?;
+            let body = loop_list(rt, body, &env, "while")?;
+            loop {
+                prim::tick(rt)?;
+                // A break or return in the condition leaves too
+                let res = run_block(rt, &cond, Rc::clone(&env), false)?;
+                let res = if res.is_ret_value() {
+                    res
+                } else if prim::condition(res, "while")? {
+                    run_block(rt, &body, Rc::clone(&env), false)?
+                } else {
+                    break Null;
+                };
+                if let Some(exit) = prim::loop_exit(res) {
+                    break exit;
                 }
-                _ => interp_error("Extend error, illegal operator"),
             }
         }
-        If(box b, r1, r2) => {
-            if let ValType::Boolean(b) = interp_exp(input, b, Rc::clone(&env)) {
-                if b {
-                    interp_exp(input, Run(r1), Rc::clone(&env))
-                } else {
-                    interp_exp(input, Run(r2), Rc::clone(&env))
+        For(control, body) => {
+            let control = interp_exp(rt, control, Rc::clone(&env))?;
+            let (var, steps) = prim::for_range(control, &env)?;
+            let body = loop_list(rt, body, &env, "for")?;
+            let mut res = Null;
+            for n in steps {
+                prim::tick(rt)?;
+                prim::make(var.clone(), Num(n.into()), &env);
+                if let Some(exit) = prim::loop_exit(run_block(rt, &body, Rc::clone(&env), false)?) {
+                    res = exit;
+                    break;
                 }
-            } else {
-                interp_error("If error, condition not boolean")
             }
+            res
         }
-        Read => {
-            if let Some(str) = input.next_word() {
-                ValType::Str(str)
-            } else {
-                interp_error("Read error")
+        Foreach(list, func) => {
+            let items = prim::items(interp_exp(rt, list, Rc::clone(&env))?)?;
+            let (name, callee) = prim::each_callee(interp_exp(rt, func, Rc::clone(&env))?, &env)?;
+            let global = env.borrow().get_global();
+            for item in items {
+                prim::tick(rt)?;
+                match &callee {
+                    Callee::Host(host) => {
+                        (host.func)(&[item])?;
+                    }
+                    Callee::Mua(func) => {
+                        let call = TailCall {
+                            name: name.clone(),
+                            func: func.clone(),
+                            args: vec![item],
+                            pos: None,
+                        };
+                        invoke(rt, call, Rc::clone(&global))?;
+                    }
+                }
             }
+            Null
         }
-        Return(box expr) => {
-            let res = Retv(Box::new(interp_exp(input, expr, Rc::clone(&env))));
+        Break => Leave(Flow::Break),
+        Continue => Leave(Flow::Continue),
+        Read => prim::read(rt)?,
+        ReadList => prim::read_list(rt)?,
+        Return(expr) => {
+            let res = Retv(Box::new(interp_exp(rt, expr, Rc::clone(&env))?));
             // println!("Debug - Return val: {:?}", res);
             res
         }
-        Export(box expr) => {
-            if let ValType::Str(s) = interp_exp(input, expr, Rc::clone(&env)) {
-                env.borrow_mut().export(s);
-            } else {
-                interp_error("Export error, illegal variables");
-            }
-
-            ValType::Null
+        Export(expr) => {
+            let name = prim::name(interp_exp(rt, expr, Rc::clone(&env))?, "export")?;
+            prim::export(name, &env)?
         }
 
-        Function(op, exprs) => {
-            // println!("Debug - run func: {}", op);
-            let cenv = Rc::new(RefCell::new(SymTable::new(
-                Some(env.borrow().get_global()),
-                None,
-            )));
-
-            let func: ValType;
-            {
-                func = env.borrow().lookup(&op).unwrap();
-            }
-
-            if let ValType::List(_, ListType::Function(closenv, func_params, func_body)) = func {
-                let mut params = VecDeque::new();
-                let func_body = vec2str(&func_body);
-                exprs
-                    .into_iter()
-                    .map(|expr| params.push_back(interp_exp(input, expr, Rc::clone(&env))))
-                    .count();
-                closenv
-                    .into_iter()
-                    .map(|c| cenv.borrow_mut().bind(c.name, c.val))
-                    .count();
-                func_params
-                    .into_iter()
-                    .map(|param_name| {
-                        cenv.borrow_mut()
-                            .bind(param_name, params.pop_front().unwrap())
-                    })
-                    .count();
-
-                let mut cinput = Input::string(&func_body[1..func_body.len() - 1]);
+        Function(op, exprs) => call(rt, op, exprs, None, env)?,
+        Code(_) => run_list(rt, expr, env)?,
+        Save(filename) => {
+            let filename = interp_exp(rt, filename, Rc::clone(&env))?;
+            prim::save(rt, filename, &env)?
+        }
+        Load(filename) => {
+            let filename = interp_exp(rt, filename, Rc::clone(&env))?;
+            let (mut input, path) = prim::load(rt, filename)?;
+            prim::in_dir(rt, &path, |rt| interpretor(rt, &mut input, Rc::clone(&env)))?;
 
-                interpretor(&mut cinput, Rc::clone(&cenv))
+            ValType::Boolean(true)
+        }
+        SaveImage(filename) => {
+            let filename = interp_exp(rt, filename, Rc::clone(&env))?;
+            prim::save_image(rt, filename, &env)?
+        }
+        LoadImage(filename) => {
+            let filename = interp_exp(rt, filename, Rc::clone(&env))?;
+            prim::load_image(rt, filename, &env)?
+        }
+        Erall => prim::erall(&env),
+        At(pos, expr) => {
+            let res = if let Function(op, exprs) = &**expr {
+                call(rt, op, exprs, pos.clone(), env)
             } else {
-                interp_error("Function error, no function found")
-            }
+                interp_exp(rt, expr, env)
+            };
+            return res.map_err(|e| e.at(pos));
         }
-        Save(box filename) => {
-            let filename = interp_exp(input, filename, Rc::clone(&env)).to_string();
-            let mut file = File::create(format!("../{}", filename)).unwrap();
+        Nop => ValType::Null,
+        Exit => prim::bye()?,
+    };
+    Ok(res)
+}
 
-            for (key, val) in env.borrow().get_keys_values() {
-                let str = format!("make \"{} {}\n", key, val.to_origin());
-                file.write_all(str.as_bytes()).unwrap();
-            }
-            ValType::Str(filename)
-        }
-        Load(box filename) => {
-            let filename = interp_exp(input, filename, Rc::clone(&env)).to_string();
-            let mut file = File::open(format!("../{}", filename)).unwrap();
+fn call(
+    rt: &mut Runtime,
+    op: &str,
+    exprs: &[Expr],
+    pos: Option<Pos>,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    let (func, args) = match prepare_call(rt, op, exprs, &env)? {
+        (Callee::Host(host), args) => return (host.func)(&args),
+        (Callee::Mua(func), args) => (func, args),
+    };
+    let global = env.borrow().get_global();
+    invoke(
+        rt,
+        TailCall {
+            name: op.to_string(),
+            func,
+            args,
+            pos,
+        },
+        global,
+    )
+}
 
-            let mut content = String::new();
-            file.read_to_string(&mut content).unwrap();
+// Find the function and evaluate its arguments
+fn prepare_call(
+    rt: &mut Runtime,
+    op: &str,
+    exprs: &[Expr],
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<(Callee, Vec<ValType>), MuaError> {
+    let func = prim::function(op, exprs.len(), env)?;
+    let mut args = vec![];
+    for expr in exprs {
+        args.push(interp_exp(rt, expr, Rc::clone(env))?);
+    }
+    Ok((func, args))
+}
 
-            let mut input = Input::string(&content);
-            interpretor(&mut input, Rc::clone(&env));
+// Run a function, and then each call it returns in its place
+pub fn invoke(
+    rt: &mut Runtime,
+    mut call: TailCall,
+    global: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    prim::check_depth(rt)?;
 
-            ValType::Boolean(true)
-        }
-        Erall => {
-            env.borrow_mut().clear_all();
-            ValType::Boolean(true)
-        }
-        Nop => ValType::Null,
-        Exit => {
-            println!(
-                "{}",
-                Color::RGB(0x33, 0xff, 0xcc).paint(
-                    "
-            ____
-            | __ ) _   _  ___
-            |  _ \\| | | |/ _ \\
-            | |_) | |_| |  __/
-            |____/ \\__, |\\___|
-                   |___/
-            "
-                )
-            );
-            exit(0);
+    loop {
+        let (func_body, cenv) = prim::bind(call.func, &call.args, &global);
+        rt.stack.push(Frame {
+            name: call.name,
+            args: call.args,
+            pos: call.pos,
+        });
+        let res = run_block(rt, &func_body, cenv, true).map_err(|mut e| {
+            // Only the innermost call sees the whole stack
+            if e.backtrace.is_empty() {
+                e.backtrace = rt.stack.iter().rev().cloned().collect();
+            }
+            e
+        });
+        rt.stack.pop();
+
+        match res? {
+            Tail(box next) => call = next,
+            Retv(box res) => return Ok(res),
+            res => return prim::outside_loop(res),
         }
     }
 }
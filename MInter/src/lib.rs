@@ -0,0 +1,326 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-11 09:47:20
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-11 09:47:20
+ * @Description: MUA interpreter, to be embedded
+ * @Copyright: Copyright (c) 2022
+ */
+#![feature(box_patterns)]
+extern crate ansi_term;
+extern crate lazy_static;
+extern crate num_traits;
+extern crate ordered_float;
+extern crate stacker;
+
+mod cmdin;
+mod error;
+mod helper;
+mod image;
+mod interp;
+mod output;
+mod parser;
+mod prim;
+mod source;
+mod syntax;
+mod vm;
+
+pub use crate::cmdin::{Input, Pos};
+pub use crate::error::{ErrorKind, MuaError};
+pub use crate::interp::{Files, Frame, Limits, Runtime, MAX_DEPTH};
+pub use crate::output::{Buffer, Capture, Output, Stdio, Stream};
+pub use crate::parser::Program;
+pub use crate::syntax::{Items, ListType, SymTable, ValType};
+
+use crate::parser::{is_name, parse, parse_program};
+use crate::prim::Callee;
+use crate::syntax::{Expr, Host, TailCall, KEYWORD};
+use std::cell::{Ref, RefCell};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+// How statements are run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    // Walk the syntax tree
+    Tree,
+    // Compile to bytecode for the vm
+    Vm,
+}
+
+// A MUA session, its global names live as long as it does.
+// The tree engine recurses on the native stack for MUA calls, deep
+// recursions want a thread with a large stack, else they stop early
+// with a Recursion Limit error
+pub struct Interpreter {
+    global: Rc<RefCell<SymTable>>,
+    rt: Runtime,
+    engine: Engine,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        let global = Rc::new(RefCell::new(SymTable::new(None, None)));
+        global.borrow_mut().set_global(Some(Rc::clone(&global)));
+        global.borrow_mut().predefine();
+
+        Interpreter {
+            global,
+            rt: Runtime::new(),
+            engine: Engine::Tree,
+        }
+    }
+
+    // Fix the sequence of random, so that runs can be reproduced. It's
+    // taken from the clock otherwise
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rt.random = prim::seed_state(seed);
+    }
+
+    pub fn set_engine(&mut self, engine: Engine) {
+        self.engine = engine;
+    }
+
+    // Where print and reports go, stdout and stderr by default
+    pub fn set_output(&mut self, output: Box<dyn Output>) {
+        self.rt.output = output;
+    }
+
+    // The step budget is per eval, eval_next or call_function
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.rt.limits = limits;
+    }
+
+    pub fn limits(&self) -> Limits {
+        self.rt.limits
+    }
+
+    // What save and load may open, anything by default
+    pub fn set_files(&mut self, files: Files) {
+        self.rt.files = files;
+    }
+
+    // Where read and readlist take words from, stdin by default
+    pub fn set_input(&mut self, data: Input<'static>) {
+        self.rt.data = data;
+    }
+
+    // A flag which stops what runs at its next step when set, from any
+    // thread. The run fails with Interrupted, one set while nothing runs
+    // is dropped when the next run starts
+    pub fn interrupt(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.rt.interrupt)
+    }
+
+    pub fn output(&mut self) -> &mut dyn Output {
+        self.rt.output.as_mut()
+    }
+
+    // Write err and its backtrace to the diagnostics stream
+    pub fn report(&mut self, err: &MuaError) -> Result<(), MuaError> {
+        Ok(output::report(self.output(), err)?)
+    }
+
+    pub fn report_backtrace(&mut self, backtrace: &[Frame]) -> Result<(), MuaError> {
+        Ok(output::report_backtrace(self.output(), backtrace)?)
+    }
+
+    // Parse and run the next statement of input, None at its end
+    pub fn eval_next(&mut self, input: &mut Input) -> Result<Option<ValType>, MuaError> {
+        self.start();
+        self.next(input)
+    }
+
+    fn start(&mut self) {
+        self.rt.steps = 0;
+        self.rt.interrupt.store(false, Ordering::Relaxed);
+    }
+
+    fn next(&mut self, input: &mut Input) -> Result<Option<ValType>, MuaError> {
+        match parse(input, Rc::clone(&self.global))? {
+            Some(expr) => Ok(Some(self.exec(&expr)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn exec(&mut self, expr: &Expr) -> Result<ValType, MuaError> {
+        let res = match self.engine {
+            Engine::Tree => interp::interp_exp(&mut self.rt, expr, Rc::clone(&self.global)),
+            Engine::Vm => vm::run(&mut self.rt, expr, Rc::clone(&self.global)),
+        };
+        prim::outside_loop(res?)
+    }
+
+    // Group the next statement of input without running it, written
+    // with each call in parentheses. None at the end of input
+    pub fn parse_next(&self, input: &mut Input) -> Result<Option<String>, MuaError> {
+        Ok(parse(input, Rc::clone(&self.global))?.map(|expr| expr.to_string()))
+    }
+
+    // Run all of input, returns the value of the last statement
+    pub fn eval(&mut self, input: &mut Input) -> Result<ValType, MuaError> {
+        self.start();
+        let mut res = ValType::Null;
+        while let Some(val) = self.next(input)? {
+            res = val;
+        }
+        Ok(res)
+    }
+
+    pub fn eval_str(&mut self, code: &str) -> Result<ValType, MuaError> {
+        self.eval(&mut Input::string(code))
+    }
+
+    // Group a whole file into statements without running it, its syntax
+    // errors are all in the program. See Program for how names are taken
+    pub fn parse_file(&mut self, path: &str) -> Result<Program, MuaError> {
+        let mut program = parse_program(&mut Input::file(path)?, &self.global);
+        program.dir = std::path::Path::new(path)
+            .parent()
+            .map(|dir| dir.to_path_buf());
+        Ok(program)
+    }
+
+    // Run a program with no syntax errors, returns the value of the last
+    // statement. Files it saves or loads are next to it
+    pub fn run_program(&mut self, program: &Program) -> Result<ValType, MuaError> {
+        if let Some(err) = program.errors().first() {
+            return Err(err.clone());
+        }
+        self.start();
+        let outer = std::mem::replace(&mut self.rt.dir, program.dir.clone());
+        let mut res = Ok(ValType::Null);
+        let mut start = 0;
+        while start < program.block.len() {
+            let stmt = match program.block.statement(start, &self.global) {
+                Ok(Some(stmt)) => stmt,
+                Ok(None) => break,
+                Err(err) => {
+                    res = Err(err);
+                    break;
+                }
+            };
+            res = self.exec(&stmt.expr);
+            if res.is_err() {
+                break;
+            }
+            start = stmt.end;
+        }
+        self.rt.dir = outer;
+        res
+    }
+
+    // Nothing runs if the file has a syntax error
+    pub fn eval_file(&mut self, path: &str) -> Result<ValType, MuaError> {
+        let program = self.parse_file(path)?;
+        self.run_program(&program)
+    }
+
+    // Write all global names to the file at path, functions and what
+    // they closed over too. Unlike saveimage, path is not checked
+    // against set_files
+    pub fn save_image(&mut self, path: &str) -> Result<(), MuaError> {
+        Ok(std::fs::write(path, image::write(&self.global.borrow()))?)
+    }
+
+    // The global names become those of the image at path
+    pub fn load_image(&mut self, path: &str) -> Result<(), MuaError> {
+        image::read(&std::fs::read(path)?, &self.global)
+    }
+
+    // The global names, to look at what's made
+    pub fn globals(&self) -> Ref<'_, SymTable> {
+        self.global.borrow()
+    }
+
+    // Names which may be called: keywords, registered primitives and
+    // global functions, sorted
+    pub fn operations(&self) -> Vec<String> {
+        let global = self.global.borrow();
+        let mut names: Vec<_> = KEYWORD
+            .keys()
+            .map(|name| name.to_string())
+            .chain(global.host_names().cloned())
+            .chain(global.functions().map(|(name, _)| name.clone()))
+            .collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    // The statements save would write for name alone
+    pub fn source(&self, name: &str) -> Result<String, MuaError> {
+        Ok(source::definition(name, &self.get_var(name)?))
+    }
+
+    // Start over with the names a new session has, primitives registered
+    // are kept
+    pub fn reset(&mut self) {
+        let mut global = self.global.borrow_mut();
+        global.clear_all();
+        global.predefine();
+    }
+
+    pub fn get_var(&self, name: &str) -> Result<ValType, MuaError> {
+        prim::thing(name, &self.global)
+    }
+
+    // Same as a make at the top level, returns the value
+    pub fn set_var(&mut self, name: &str, val: ValType) -> Result<ValType, MuaError> {
+        if !is_name(name) {
+            return prim::interp_error(
+                ErrorKind::InvalidName,
+                format!("\"{}\" is not a name", name),
+            );
+        }
+        Ok(prim::make(name.to_string(), val, &self.global))
+    }
+
+    // Add a primitive named name, taking arity arguments. Code groups
+    // arguments for it like for a keyword, and it shadows MUA functions
+    pub fn register<F>(&mut self, name: &str, arity: usize, func: F) -> Result<(), MuaError>
+    where
+        F: Fn(&[ValType]) -> Result<ValType, MuaError> + 'static,
+    {
+        if !is_name(name) || KEYWORD.contains_key(name) {
+            return prim::interp_error(
+                ErrorKind::InvalidName,
+                format!("\"{}\" can't be a primitive", name),
+            );
+        }
+        self.global.borrow_mut().register(
+            name.to_string(),
+            Host {
+                arity,
+                func: Box::new(func),
+            },
+        );
+        Ok(())
+    }
+
+    // Call a global function with evaluated arguments
+    pub fn call_function(&mut self, name: &str, args: Vec<ValType>) -> Result<ValType, MuaError> {
+        let func = match prim::function(name, args.len(), &self.global)? {
+            Callee::Host(host) => return (host.func)(&args),
+            Callee::Mua(func) => func,
+        };
+        self.start();
+        let call = TailCall {
+            name: name.to_string(),
+            func,
+            args,
+            pos: None,
+        };
+        match self.engine {
+            Engine::Tree => interp::invoke(&mut self.rt, call, Rc::clone(&self.global)),
+            Engine::Vm => vm::call(&mut self.rt, call, Rc::clone(&self.global)),
+        }
+    }
+}
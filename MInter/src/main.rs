@@ -6,55 +6,208 @@
  * @Description: file information
  * @Copyright: Copyright (c) 2021
  */
-#![feature(box_patterns)]
-extern crate ansi_term;
-extern crate lazy_static;
-extern crate num_traits;
-extern crate ordered_float;
-extern crate regex;
-
-mod cmdin;
-mod helper;
-mod interp;
-mod parser;
-mod syntax;
-
-pub use crate::cmdin::Input;
-pub use crate::syntax::Expr::{self, *};
-pub use crate::syntax::SymTable;
-
 use ansi_term::Color;
-use std::cell::RefCell;
-use std::io::Write;
+use minter::{Engine, ErrorKind, Files, Input, Interpreter, Limits, MuaError, Stream, MAX_DEPTH};
 use std::process::exit;
-use std::rc::Rc;
+use std::str::FromStr;
+
+mod repl;
+
+// A run ended by exit isn't reported, the process leaves
+fn report(interp: &mut Interpreter, err: &MuaError) {
+    if err.kind == ErrorKind::Exit {
+        bye(interp)
+    }
+    interp
+        .report(err)
+        .expect("Fatal error! Stderr write fails!");
+}
+
+// What a flag names fails, it's reported as errors are and nothing runs
+fn flag_error(interp: &mut Interpreter, flag: &str, err: MuaError) -> ! {
+    let msg = format!("{}: {}", flag, err.msg);
+    report(interp, &MuaError { msg, ..err });
+    exit(1)
+}
+
+fn bye(interp: &mut Interpreter) -> ! {
+    let banner = "
+            ____
+            | __ ) _   _  ___
+            |  _ \\| | | |/ _ \\
+            | |_) | |_| |  __/
+            |____/ \\__, |\\___|
+                   |___/
+            ";
+    let out = interp.output();
+    let banner = if out.styled(Stream::Out) {
+        Color::RGB(0x33, 0xff, 0xcc).paint(banner).to_string()
+    } else {
+        banner.to_string()
+    };
+    // Leaving anyway, there's nowhere left to report to
+    let _ = writeln!(out.out(), "{}", banner);
+    let _ = out.out().flush();
+    exit(0)
+}
+
+// Enough for MAX_DEPTH calls, it's only touched as calls go deeper
+const STACK_SIZE: usize = 1 << 30;
+
+// --max-depth grows the stack along, past this it's more than a thread gets
+const DEPTH_CAP: usize = 10 * MAX_DEPTH;
+
+// What the command line asks for, taken before the interpreter thread
+// starts as its stack depends on it
+#[derive(Default)]
+struct Options {
+    seed: Option<u64>,
+    engine: Option<Engine>,
+    data: Option<String>,
+    image: Option<String>,
+    limits: Limits,
+    files: Files,
+    check: bool,
+    file: Option<String>,
+}
+
+// The value of a flag, or exit telling what it expects
+fn flag_value<T: FromStr>(args: &mut impl Iterator<Item = String>, flag: &str, what: &str) -> T {
+    match args.next().and_then(|s| s.parse().ok()) {
+        Some(val) => val,
+        None => {
+            eprintln!("{} expects {}", flag, what);
+            exit(1)
+        }
+    }
+}
+
+fn options() -> Options {
+    let mut opts = Options::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => opts.seed = Some(flag_value(&mut args, &arg, "an unsigned integer")),
+            "--stdin-file" => opts.data = Some(flag_value(&mut args, &arg, "a file")),
+            "--image" => opts.image = Some(flag_value(&mut args, &arg, "a file")),
+            "--check" => opts.check = true,
+            "--no-files" => opts.files = Files::Denied,
+            "--files-in" => opts.files = Files::Within(flag_value(&mut args, &arg, "a directory")),
+            "--max-steps" => {
+                opts.limits.steps = Some(flag_value(&mut args, &arg, "a number of steps"))
+            }
+            "--max-depth" => match flag_value(&mut args, &arg, "a number of calls") {
+                depth if depth <= DEPTH_CAP => opts.limits.depth = depth,
+                _ => {
+                    eprintln!("--max-depth expects at most {}", DEPTH_CAP);
+                    exit(1)
+                }
+            },
+            "--max-size" => {
+                opts.limits.size = Some(flag_value(&mut args, &arg, "a size in items and bytes"))
+            }
+            "--engine=tree" => opts.engine = Some(Engine::Tree),
+            "--engine=vm" => opts.engine = Some(Engine::Vm),
+            _ if arg.starts_with("--engine=") => {
+                eprintln!("--engine expects vm or tree");
+                exit(1)
+            }
+            _ => opts.file = Some(arg),
+        }
+    }
+    opts
+}
 
 fn main() {
-    use crate::interp::interp_exp;
-    use crate::parser::parse;
-    let global = Rc::new(RefCell::new(SymTable::new(None, None)));
-    global.borrow_mut().set_global(Some(Rc::clone(&global)));
+    let opts = options();
+    if opts.check && opts.file.is_none() {
+        eprintln!("--check expects a file");
+        exit(1)
+    }
+    let stack_size = STACK_SIZE.max(STACK_SIZE / MAX_DEPTH * opts.limits.depth);
+    let depth = opts.limits.depth;
+    let interp = match std::thread::Builder::new()
+        .stack_size(stack_size)
+        .spawn(|| run(opts))
+    {
+        Ok(interp) => interp,
+        Err(err) => {
+            eprintln!(
+                "--max-depth {} needs a larger stack than there is: {}",
+                depth, err
+            );
+            exit(1)
+        }
+    };
+    if interp.join().is_err() {
+        exit(101)
+    }
+}
 
-    let file = std::env::args().nth(1);
-    match file {
-        Some(filename) => {
-            let mut input = Input::file(&filename);
-            while let Some(expr) = parse(&mut input, Rc::clone(&global)) {
-                interp_exp(&mut input, expr, Rc::clone(&global));
+fn run(opts: Options) {
+    let mut interp = Interpreter::new();
+    if let Some(seed) = opts.seed {
+        interp.set_seed(seed);
+    }
+    if let Some(engine) = opts.engine {
+        interp.set_engine(engine);
+    }
+    if let Some(path) = opts.data {
+        match Input::file(&path) {
+            Ok(data) => interp.set_input(data),
+            Err(err) => {
+                let err = MuaError::new(ErrorKind::IoError, err.to_string());
+                flag_error(&mut interp, "--stdin-file", err)
             }
-            exit(0)
         }
-        None => {
-            let stdin = std::io::stdin();
-            let mut input = Input::console(&stdin);
-            loop {
-                print!("{}", Color::Green.paint("User>"));
-                std::io::stdout()
-                    .flush()
-                    .expect("Fatal error! Stdout flush fails!");
-                let exp = parse(&mut input, Rc::clone(&global)).unwrap();
-                interp_exp(&mut input, exp, Rc::clone(&global));
+    }
+    // The image is named on the command line, it's opened whatever
+    // files the program may touch
+    if let Some(path) = opts.image {
+        if let Err(err) = interp.load_image(&path) {
+            flag_error(&mut interp, "--image", err)
+        }
+    }
+    interp.set_limits(opts.limits);
+    interp.set_files(opts.files);
+
+    match opts.file {
+        Some(filename) => {
+            // Syntax errors are all reported before anything runs
+            let program = match interp.parse_file(&filename) {
+                Ok(program) => program,
+                Err(err) => {
+                    report(&mut interp, &err);
+                    exit(1)
+                }
+            };
+            for err in program.errors() {
+                report(&mut interp, err);
+            }
+            if opts.check {
+                if let Some((name, pos)) = program.deferred() {
+                    let at = pos
+                        .as_ref()
+                        .map_or(String::new(), |pos| format!("{}: ", pos));
+                    writeln!(
+                        interp.output().err(),
+                        "{}{} may be made as the program runs, what follows is checked as it runs",
+                        at,
+                        name
+                    )
+                    .expect("Fatal error! Stderr write fails!");
+                }
+                exit(if program.errors().is_empty() { 0 } else { 1 })
+            }
+            if !program.errors().is_empty() {
+                exit(1)
             }
+            if let Err(err) = interp.run_program(&program) {
+                report(&mut interp, &err);
+                exit(1)
+            }
+            exit(0)
         }
+        None => repl::run(interp),
     }
 }
@@ -0,0 +1,135 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-12 14:02:37
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-12 14:02:37
+ * @Description: Where the interpreter writes to
+ * @Copyright: Copyright (c) 2022
+ */
+
+use crate::error::MuaError;
+use crate::interp::Frame;
+use ansi_term::Color;
+use std::cell::RefCell;
+use std::io::{self, IsTerminal, Write};
+use std::rc::Rc;
+
+// Two streams, one for what programs print and one for diagnostics
+pub trait Output {
+    fn out(&mut self) -> &mut dyn Write;
+    fn err(&mut self) -> &mut dyn Write;
+
+    // Whether to color what's written to stream, only terminals want it
+    fn styled(&self, _stream: Stream) -> bool {
+        false
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Stream {
+    Out,
+    Err,
+}
+
+// The process' stdout and stderr
+pub struct Stdio {
+    out: io::Stdout,
+    err: io::Stderr,
+}
+
+impl Default for Stdio {
+    fn default() -> Self {
+        Stdio {
+            out: io::stdout(),
+            err: io::stderr(),
+        }
+    }
+}
+
+impl Output for Stdio {
+    fn out(&mut self) -> &mut dyn Write {
+        &mut self.out
+    }
+
+    fn err(&mut self) -> &mut dyn Write {
+        &mut self.err
+    }
+
+    fn styled(&self, stream: Stream) -> bool {
+        match stream {
+            Stream::Out => self.out.is_terminal(),
+            Stream::Err => self.err.is_terminal(),
+        }
+    }
+}
+
+// A buffer shared by its clones, so one stays with the caller while the
+// interpreter writes to another
+#[derive(Debug, Clone, Default)]
+pub struct Buffer(Rc<RefCell<Vec<u8>>>);
+
+impl Buffer {
+    // Take what's written so far
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&self.0.take()).into_owned()
+    }
+}
+
+impl Write for Buffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Both streams kept in buffers, for tests and embedders
+#[derive(Debug, Clone, Default)]
+pub struct Capture {
+    pub out: Buffer,
+    pub err: Buffer,
+}
+
+impl Output for Capture {
+    fn out(&mut self) -> &mut dyn Write {
+        &mut self.out
+    }
+
+    fn err(&mut self) -> &mut dyn Write {
+        &mut self.err
+    }
+}
+
+pub fn report(output: &mut dyn Output, err: &MuaError) -> io::Result<()> {
+    let kind = err.kind.to_string();
+    let kind = if output.styled(Stream::Err) {
+        Color::Red.paint(kind).to_string()
+    } else {
+        kind
+    };
+    match &err.pos {
+        Some(pos) => writeln!(output.err(), "{}: {} - {}", pos, kind, err.msg)?,
+        None => writeln!(output.err(), "{} - {}", kind, err.msg)?,
+    }
+    report_backtrace(output, &err.backtrace)
+}
+
+// Deep recursions are cut, their frames look all the same
+const BACKTRACE_SHOWN: usize = 20;
+
+pub fn report_backtrace(output: &mut dyn Output, backtrace: &[Frame]) -> io::Result<()> {
+    let err = output.err();
+    if !backtrace.is_empty() {
+        writeln!(err, "Backtrace (most recent call first):")?;
+    }
+    for (i, frame) in backtrace.iter().enumerate().take(BACKTRACE_SHOWN) {
+        writeln!(err, "{:>4}: {}", i, frame)?;
+    }
+    if backtrace.len() > BACKTRACE_SHOWN {
+        writeln!(err, "      ... {} more", backtrace.len() - BACKTRACE_SHOWN)?;
+    }
+    Ok(())
+}
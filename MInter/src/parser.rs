@@ -7,329 +7,886 @@
  * @Copyright: Copyright (c) 2021
  */
 
-pub use Sexpr::{Atom, List};
+pub use Sexpr::{Atom, List, Literal};
 
+use crate::cmdin::{Pos, Spanned, Token};
+use crate::error::{ErrorKind, MuaError};
+use crate::prim;
 use crate::syntax::*;
+use crate::vm::Chunk;
 use crate::Input;
-use crate::vecdeque;
-use ansi_term::Color;
-use regex::Regex;
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
 use std::rc::Rc;
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum Sexpr {
-    Atom(String),
+    Atom(Token, Option<Pos>),
+    // List literal, kept as the tokens it is made of, brackets included
+    Literal(Vec<Spanned>),
     List(Vec<Sexpr>),
 }
 
-fn is_valid_op(key: &String, env: Rc<RefCell<SymTable>>) -> Option<i32> {
+fn is_valid_op(key: &str, env: Rc<RefCell<SymTable>>) -> Option<i32> {
     KEYWORD
-        .get(key.as_str())
+        .get(key)
         .map(|n| n.to_owned())
-        .or(env.borrow().is_func(key))
+        .or_else(|| arity(key, &env.borrow()))
+}
+
+// Of a name which is not a keyword
+fn arity(key: &str, env: &SymTable) -> Option<i32> {
+    env.host(key)
+        .map(|host| host.arity as i32)
+        .or_else(|| env.is_func(key))
 }
 
 fn is_keyword(sexpr: Option<&Sexpr>) -> bool {
-    if let Some(Atom(op)) = sexpr {
+    if let Some(Atom(Token::Name(op), _)) = sexpr {
         KEYWORD.get(op.as_str()).is_some()
     } else {
         false
     }
 }
 
-fn parse_error(content: &str) -> Expr {
-    println!("{} - {}", Color::Red.paint("Parse Error"), content);
-    Expr::Nop
+fn parse_error<T>(
+    kind: ErrorKind,
+    content: impl Into<String>,
+    pos: &Option<Pos>,
+) -> Result<T, MuaError> {
+    Err(MuaError::new(kind, content).at(pos))
 }
 
+// Names looked up as operations while grouping, with the arity they had
+type Deps = Vec<(String, Option<i32>)>;
+
 // Read until a command line is complete
-pub fn parse_string(input: &mut Input, env: Rc<RefCell<SymTable>>) -> Option<Sexpr> {
+pub fn parse_string(
+    input: &mut Input,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<Option<Sexpr>, MuaError> {
+    read_sexpr(input, env, &mut vec![])
+}
+
+fn read_sexpr(
+    input: &mut Input,
+    env: Rc<RefCell<SymTable>>,
+    deps: &mut Deps,
+) -> Result<Option<Sexpr>, MuaError> {
     let mut stack = vec![];
     let mut list = vec![];
 
     let mut param_num = 0;
     let mut param_stack = vec![];
 
-    let mut literal = String::new(); // String to store a list
+    let mut literal = vec![]; // Tokens to store a list
     let mut braket_num = 0; // Used to read list.
 
-    let mut atom: bool;
     let mut valid_op: bool;
 
-    while let Some(word) = &input.next_word() {
-        if let Some(n) = is_valid_op(word, Rc::clone(&env)) {
-            valid_op = true;
-
+    while let Some((token, pos)) = input.next_token() {
+        if let (0, Token::Comment(_)) = (braket_num, &token) {
+            continue;
+        }
+        if braket_num > 0 || token == Token::LBracket {
+            match token {
+                Token::LBracket => braket_num += 1,
+                Token::RBracket => braket_num -= 1,
+                _ => (),
+            }
+            literal.push((token, pos));
             if braket_num > 0 {
-                // Words in list are atoms
-                atom = true;
-            } else {
-                // When needed parameter's number is zero
-                // the op shall be taken as an atom
-                atom = n <= 0;
+                continue;
+            }
+            list.push(Literal(std::mem::take(&mut literal)));
+            valid_op = false;
+        } else if token == Token::RBracket {
+            return parse_error(ErrorKind::ParseError, "unmatched \"]\"", &pos);
+        } else {
+            let op = match &token {
+                Token::Name(name) => {
+                    let op = is_valid_op(name, Rc::clone(&env));
+                    if is_name(name) && KEYWORD.get(name.as_str()).is_none() {
+                        deps.push((name.to_owned(), op));
+                    }
+                    op
+                }
+                _ => None,
+            };
 
+            if let Some(n) = op {
+                valid_op = true;
                 param_stack.push(param_num);
                 param_num = n;
                 stack.push(list);
                 list = vec![];
-            }
-        } else {
-            valid_op = false;
-            atom = true;
-        }
 
-        if !atom {
-            list.push(Atom(word.into()));
-        } else {
-            // Check list first
-            if word.starts_with("[") {
-                braket_num += word.matches("[").count() as i32;
-                if braket_num == 1 {
-                    literal.clear();
+                // When needed parameter's number is zero
+                // the op shall be taken as an atom
+                if n > 0 {
+                    list.push(Atom(token, pos));
+                    continue;
                 }
+            } else {
+                valid_op = false;
             }
+            list.push(Atom(token, pos));
+        }
 
-            if word.ends_with("]") {
-                braket_num -= word.matches("]").count() as i32;
-                if braket_num == 0 {
-                    literal.extend([word, " "]);
-                    braket_num = -1; // Act as a flag
-                }
+        if param_num == 0 && !valid_op {
+            // Value input
+            continue;
+        } else if param_num != 0 {
+            param_num -= 1;
+        }
+
+        while param_num <= 0 {
+            let mut nlist = stack.pop().unwrap();
+            param_num = param_stack.pop().unwrap();
+            nlist.push(List(list));
+            list = nlist;
+            if param_stack.is_empty() {
+                break;
             }
+            param_num -= 1;
+        }
 
-            if braket_num > 0 {
-                literal.extend([word, " "]);
-            } else {
-                if braket_num == -1 {
-                    list.push(Atom(literal.trim().into()));
-                    braket_num = 0; // Clear flag
-                } else {
-                    list.push(Atom(word.into()));
-                }
+        if param_stack.is_empty() {
+            return Ok(list.pop()); // Jump out of the loop
+        }
+    } // While
 
-                if param_num == 0 && !valid_op {
-                    // Value input
-                    continue;
-                } else if param_num != 0 {
-                    param_num -= 1;
-                }
+    // Input ends before the command line is complete
+    if braket_num > 0 {
+        parse_error(
+            ErrorKind::ParseError,
+            "unexpected end of input, \"]\" expected",
+            &literal[0].1,
+        )
+    } else if let (false, Some(Atom(op, pos))) = (param_stack.is_empty(), list.first()) {
+        parse_error(
+            ErrorKind::ArityError,
+            format!(
+                "{} expects {} arguments, found {}",
+                op,
+                list.len() as i32 - 1 + param_num,
+                list.len() - 1
+            ),
+            pos,
+        )
+    } else {
+        Ok(list.pop())
+    }
+}
 
-                while param_num <= 0 {
-                    let mut nlist = stack.pop().unwrap();
-                    param_num = param_stack.pop().unwrap();
-                    nlist.push(List(list));
-                    list = nlist;
-                    if param_stack.is_empty() {
-                        break;
-                    }
-                    param_num -= 1;
+// A list `[[params] [body]]` with words for params is a function
+fn function_parts(list: &VecDeque<ValType>) -> Option<(Vec<String>, &VecDeque<ValType>)> {
+    match (list.len(), list.front(), list.back()) {
+        (2, Some(ValType::List(params, _)), Some(ValType::List(body, _))) => params
+            .iter()
+            .map(|param| match param {
+                ValType::Str(param) => Some(param.clone()),
+                _ => None,
+            })
+            .collect::<Option<_>>()
+            .map(|params| (params, &**body)),
+        _ => None,
+    }
+}
+
+pub fn is_function(list: &VecDeque<ValType>) -> bool {
+    function_parts(list).is_some()
+}
+
+// Tokens are a whole list literal, its brackets match. Comments are
+// left out of the list, a function keeps them in its code
+fn solve_list(tokens: &[Spanned]) -> ValType {
+    let mut stack = vec![];
+    let mut list = VecDeque::new();
+    let mut inner = vec![]; // Where the lists right inside start and end
+    let mut start = 0;
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LBracket => {
+                if stack.len() == 1 {
+                    start = i;
+                }
+                stack.push(std::mem::take(&mut list));
+            }
+            Token::RBracket => {
+                let items = std::mem::replace(&mut list, stack.pop().unwrap());
+                list.push_back(ValType::List(items.into(), ListType::Ordinary));
+                if stack.len() == 1 {
+                    inner.push((start, i));
                 }
             }
+            Token::Comment(_) => (),
+            token => list.push_back(ValType::Str(token.to_string())),
         }
-        if param_stack.is_empty() {
-            break; // Jump out of the loop
+    }
+    let list = list.pop_back().unwrap().into_list();
+
+    match function_parts(&list) {
+        Some((params, body)) => {
+            let (start, end) = inner[1];
+            let code = Block::new(tokens[start + 1..end].to_vec()).with_params(&params);
+            let body = body.clone();
+            ValType::List(list, ListType::Function(None, params, body, Rc::new(code)))
         }
-    } // While
-    // println!("Debug - parse string res: {:?}", list);
-    list.pop()
+        None => ValType::List(list, ListType::Ordinary),
+    }
+}
+
+pub fn is_num(s: &str) -> bool {
+    let x = s.strip_prefix('-').unwrap_or(s);
+    !x.is_empty() && x.chars().all(|c| c.is_ascii_digit() || c == '.')
 }
 
-fn parse_list(slist: &str) -> VecDeque<ValType> {
-    let mut stack = vecdeque![];
-    let mut list = vecdeque![];
-    let mut word = "".to_string();
-    let mut word_flag = false;
+// Words can't have whitespace in code, a word literal writes it
+// as \s, \t, \n or \u{hex}, and a backslash as \\
+pub fn escape(word: &str) -> String {
+    let mut escaped = String::new();
+    for c in word.chars() {
+        match c {
+            ' ' => escaped.push_str("\\s"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_whitespace() => escaped.push_str(&format!("\\u{{{:x}}}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
 
-    for c in slist.chars() {
+// Other backslashes are kept as they are
+fn unescape(word: &str) -> String {
+    let mut word = word;
+    let mut unescaped = String::new();
+    while let Some(i) = word.find('\\') {
+        unescaped.push_str(&word[..i]);
+        let rest = &word[i + 1..];
+        let (c, len) = match rest.chars().next() {
+            Some('s') => (Some(' '), 1),
+            Some('t') => (Some('\t'), 1),
+            Some('n') => (Some('\n'), 1),
+            Some('\\') => (Some('\\'), 1),
+            Some('u') => match rest[1..].strip_prefix('{').and_then(|r| r.split_once('}')) {
+                Some((hex, _)) => (
+                    u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                    hex.len() + 3,
+                ),
+                None => (None, 0),
+            },
+            _ => (None, 0),
+        };
         match c {
-            '[' => {
-                stack.push_back(list);
-                list = vecdeque![];
+            Some(c) => {
+                unescaped.push(c);
+                word = &rest[len..];
             }
-            ']' => {
-                if word_flag {
-                    word_flag = false;
-                    list.push_back(ValType::Str(word.clone()));
-                    word.clear();
-                }
-                let mut nlist = stack.pop_back().unwrap();
-                nlist.push_back(ValType::List(list, ListType::Ordinary));
-                list = nlist;
+            None => {
+                unescaped.push('\\');
+                word = rest;
             }
-            ' ' => {
-                if word_flag {
-                    word_flag = false;
-                    list.push_back(ValType::Str(word.clone()));
-                    word.clear();
-                }
+        }
+    }
+    unescaped.push_str(word);
+    unescaped
+}
+
+pub fn is_name(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+fn is_bool(s: &str) -> bool {
+    s == "true" || s == "false"
+}
+
+// List literals to be run are kept as code, so they are parsed only once
+fn parse_code(sexpr: &Sexpr) -> Result<Expr, MuaError> {
+    match sexpr {
+        Literal(tokens) => Ok(Code(Rc::new(Block::new(
+            tokens[1..tokens.len() - 1].to_vec(),
+        )))),
+        _ => parse_sexpr(sexpr),
+    }
+}
+
+pub fn parse_sexpr(sexpr: &Sexpr) -> Result<Expr, MuaError> {
+    prim::check_stack()?;
+    let expr = match sexpr {
+        Atom(token, pos) => match token {
+            Token::Number(s) => Value(ValType::Num(s.parse().unwrap())),
+            Token::Word(s) => Value(ValType::Str(unescape(s))),
+            Token::Var(s) => At(pos.clone(), Box::new(Var(s.to_owned()))),
+            Token::Name(s) if is_bool(s) => Value(ValType::Boolean(s == "true")),
+            Token::Name(s) if is_name(s) => {
+                return parse_error(
+                    ErrorKind::UndefinedName,
+                    format!("undefined name \"{}\"", s),
+                    pos,
+                );
             }
             _ => {
-                word_flag = true;
-                word.push(c);
+                return parse_error(
+                    ErrorKind::ParseError,
+                    format!("unrecognized word {}", token),
+                    pos,
+                );
             }
+        },
+        Literal(tokens) => Value(solve_list(tokens)),
+        List(v) => {
+            let pos = match v.first() {
+                Some(Atom(_, pos)) => pos.clone(),
+                _ => None,
+            };
+            At(
+                pos.clone(),
+                Box::new(parse_operation(v).map_err(|e| e.at(&pos))?),
+            )
+        }
+    };
+    Ok(expr)
+}
+
+fn parse_operation(v: &[Sexpr]) -> Result<Expr, MuaError> {
+    let expr = {
+        if is_keyword(v.first()) {
+            match v {
+                // 3 parameters
+                [Atom(Token::Name(op), _), param1, param2, param3] => match op.as_str() {
+                    "if" => If(
+                        Box::new(parse_sexpr(param1)?),
+                        Box::new(parse_code(param2)?),
+                        Box::new(parse_code(param3)?),
+                    ),
+                    _ => {
+                        return parse_error(
+                            ErrorKind::ParseError,
+                            format!("unrecognized operation {}", op),
+                            &None,
+                        )
+                    }
+                },
+                // 2 parameters
+                [Atom(Token::Name(op), _), param1, param2] => match op.as_str() {
+                    "make" => Make(
+                        Box::new(parse_sexpr(param1)?),
+                        Box::new(parse_sexpr(param2)?),
+                    ),
+                    "repeat" => Repeat(
+                        Box::new(parse_sexpr(param1)?),
+                        Box::new(parse_code(param2)?),
+                    ),
+                    "while" => While(Box::new(parse_code(param1)?), Box::new(parse_code(param2)?)),
+                    "for" => For(
+                        Box::new(parse_sexpr(param1)?),
+                        Box::new(parse_code(param2)?),
+                    ),
+                    "foreach" => Foreach(
+                        Box::new(parse_sexpr(param1)?),
+                        Box::new(parse_sexpr(param2)?),
+                    ),
+                    op => {
+                        let a = Box::new(parse_sexpr(param1)?);
+                        let b = Box::new(parse_sexpr(param2)?);
+                        if let Some(op) = ExtendOp::parse(op) {
+                            Extend(op, a, b)
+                        } else if let Some(op) = CalcOp::parse(op) {
+                            Calc(op, a, b)
+                        } else if let Some(op) = CompOp::parse(op) {
+                            Comp(op, a, b)
+                        } else if let Some(op) = LogicOp::parse(op) {
+                            Logic(op, a, b)
+                        } else {
+                            return parse_error(
+                                ErrorKind::ParseError,
+                                format!("unrecognized operation {}", op),
+                                &None,
+                            );
+                        }
+                    }
+                },
+                // 1 parameters
+                [Atom(Token::Name(op), _), param] => match op.as_str() {
+                    "print" => Print(Box::new(parse_sexpr(param)?)),
+                    "thing" => Thing(Box::new(parse_sexpr(param)?)),
+                    "erase" => Erase(Box::new(parse_sexpr(param)?)),
+                    "run" => Run(Box::new(parse_code(param)?)),
+                    "save" => Save(Box::new(parse_sexpr(param)?)),
+                    "load" => Load(Box::new(parse_sexpr(param)?)),
+                    "saveimage" => SaveImage(Box::new(parse_sexpr(param)?)),
+                    "loadimage" => LoadImage(Box::new(parse_sexpr(param)?)),
+                    "not" => Logic(
+                        LogicOp::Not,
+                        Box::new(parse_sexpr(param)?),
+                        Box::new(Value(ValType::Boolean(true))),
+                    ),
+                    "return" => Return(Box::new(parse_sexpr(param)?)),
+                    "export" => Export(Box::new(parse_sexpr(param)?)),
+                    op => {
+                        let a = Box::new(parse_sexpr(param)?);
+                        if let Some(op) = JudgeOp::parse(op) {
+                            Judge(op, a)
+                        } else if let Some(op) = IndexOp::parse(op) {
+                            Index(op, a)
+                        } else if let Some(op) = MathOp::parse(op) {
+                            Math(op, a)
+                        } else {
+                            return parse_error(
+                                ErrorKind::ParseError,
+                                format!("unrecognized operation {}", op),
+                                &None,
+                            );
+                        }
+                    }
+                },
+                // no parameters
+                [Atom(Token::Name(op), _)] => match op.as_str() {
+                    "nop" => Nop,
+                    "read" => Read,
+                    "readlist" => ReadList,
+                    "exit" => Exit,
+                    "erall" => Erall,
+                    "break" => Break,
+                    "continue" => Continue,
+                    _ => {
+                        return parse_error(
+                            ErrorKind::ParseError,
+                            format!("unrecognized operation {}", op),
+                            &None,
+                        )
+                    }
+                },
+                [Atom(Token::Name(op), _), params @ ..] => {
+                    return parse_error(
+                        ErrorKind::ArityError,
+                        format!(
+                            "{} expects {} arguments, found {}",
+                            op,
+                            KEYWORD[op.as_str()],
+                            params.len()
+                        ),
+                        &None,
+                    )
+                }
+                _ => return parse_error(ErrorKind::ParseError, "invalid syntax", &None),
+            }
+        } else if let Some(Atom(Token::Name(func_name), _)) = v.first() {
+            // Function
+            Function(
+                func_name.to_owned(),
+                v.iter()
+                    .skip(1)
+                    .map(parse_sexpr)
+                    .collect::<Result<_, _>>()?,
+            )
+        } else {
+            return parse_error(ErrorKind::ParseError, "invalid syntax", &None);
         }
+    };
+    Ok(expr)
+}
+
+pub fn parse(input: &mut Input, env: Rc<RefCell<SymTable>>) -> Result<Option<Expr>, MuaError> {
+    parse_string(input, Rc::clone(&env))?
+        .map(|sexpr| parse_sexpr(&sexpr))
+        .transpose()
+}
+
+// A file grouped into statements before any of it runs. Which names are
+// operations, and their arities, is taken from:
+//  - keywords, primitives and the functions the session has
+//  - functions the file makes at its top level from a literal, like
+//    `make "f [[x] [...]]`. They are known from that make on, another
+//    such make of the name changes the arity from there on, and one of
+//    another literal, `make "f 1`, makes it no function
+// A name the file may make some other way as it runs, i.e. it has a word
+// "name not in such a make or a load, ends this: what follows is grouped
+// as it's reached.
+// The bodies of functions made at the top level are grouped too, with
+// the functions as the file leaves them. Statements are grouped again
+// when they run if an arity they were grouped with has changed
+pub struct Program {
+    pub(crate) block: Rc<Block>,
+    pub(crate) stmts: Vec<Rc<Stmt>>,
+    pub(crate) errors: Vec<MuaError>,
+    pub(crate) deferred: Option<(String, Option<Pos>)>,
+    // Where names of files it saves or loads are taken from
+    pub(crate) dir: Option<PathBuf>,
+}
+
+impl Program {
+    // Statements grouped up front
+    pub fn len(&self) -> usize {
+        self.stmts.len()
     }
 
-    if let ValType::List(l, _) = list.pop_back().unwrap() {
-        l
-    } else {
-        panic!("parse list fatal error");
+    pub fn is_empty(&self) -> bool {
+        self.stmts.is_empty()
+    }
+
+    pub fn errors(&self) -> &[MuaError] {
+        &self.errors
+    }
+
+    // The name grouping stopped at, if it did
+    pub fn deferred(&self) -> Option<&(String, Option<Pos>)> {
+        self.deferred.as_ref()
     }
 }
 
-fn solve_list(s: &str) -> ValType {
-    let re = Regex::new(r"^\[ ?\[([^\]]*)\] \[(.*)\] ?\]$").unwrap();
-
-    if let Some(m) = re.captures(s) {
-        ValType::List(
-            parse_list(s),
-            ListType::Function(
-                Vec::new(),
-                m.get(1)
-                    .unwrap()
-                    .as_str()
-                    .split_whitespace()
-                    .map(|s| s.to_string())
-                    .collect(),
-                parse_list(&format!("[{}]", m.get(2).unwrap().as_str())),
-            ),
-        )
-    } else {
-        ValType::List(parse_list(s), ListType::Ordinary)
+// Names the program may make as it runs, other than with a literal
+struct Made {
+    words: HashSet<String>,
+    load: bool,
+}
+
+impl Made {
+    // Words only ever used to make names from a literal are left out
+    fn new(tokens: &[Spanned], makes: &[(usize, String, ValType)]) -> Self {
+        let mut made = Made {
+            words: HashSet::new(),
+            load: false,
+        };
+        let mut words = HashMap::new();
+        for (token, _) in tokens {
+            match token {
+                Token::Word(word) => *words.entry(unescape(word)).or_insert(0) += 1,
+                Token::Name(name) if name == "load" => made.load = true,
+                _ => (),
+            }
+        }
+        for (_, name, _) in makes {
+            if let Some(n) = words.get_mut(name) {
+                *n -= 1;
+            }
+        }
+        made.words = words
+            .into_iter()
+            .filter(|(_, n)| *n > 0)
+            .map(|(word, _)| word)
+            .collect();
+        made
+    }
+
+    // The first name grouped as a value which may be an operation later
+    fn find<'d>(&self, deps: &'d Deps, params: &[String]) -> Option<&'d str> {
+        deps.iter()
+            .find(|(name, op)| {
+                op.is_none()
+                    && !is_bool(name)
+                    && (self.load || self.words.contains(name) || params.contains(name))
+            })
+            .map(|(name, _)| name.as_str())
     }
 }
 
-pub fn is_num(s: &str) -> bool {
-    let mut x = s;
-    if s.starts_with("-") {
-        x = &s[1..];
+// Names made at the top level from a literal, with the token they're made
+// at and the value
+fn top_makes(tokens: &[Spanned]) -> Vec<(usize, String, ValType)> {
+    let mut makes = vec![];
+    let mut depth = 0;
+    for (i, (token, _)) in tokens.iter().enumerate() {
+        match token {
+            Token::LBracket => depth += 1,
+            Token::RBracket => depth -= 1,
+            Token::Name(op) if depth == 0 && op == "make" => {
+                let name = match tokens.get(i + 1) {
+                    Some((Token::Word(name), _)) => unescape(name),
+                    _ => continue,
+                };
+                let val = match tokens.get(i + 2) {
+                    Some((Token::LBracket, _)) => {
+                        let mut inner = 0;
+                        let len = tokens[i + 2..].iter().position(|(token, _)| {
+                            match token {
+                                Token::LBracket => inner += 1,
+                                Token::RBracket => inner -= 1,
+                                _ => (),
+                            }
+                            inner == 0
+                        });
+                        match len {
+                            Some(len) => solve_list(&tokens[i + 2..=i + 2 + len]),
+                            None => continue,
+                        }
+                    }
+                    Some((Token::Number(n), _)) => ValType::Num(n.parse().unwrap()),
+                    Some((Token::Word(word), _)) => ValType::Str(unescape(word)),
+                    Some((Token::Name(name), _)) if is_bool(name) => {
+                        ValType::Boolean(name == "true")
+                    }
+                    _ => continue,
+                };
+                makes.push((i, name, val));
+            }
+            _ => (),
+        }
     }
-    !x.is_empty() && x.chars().find(|&c| !(c.is_digit(10) || c == '.')).is_none()
+    makes
 }
 
-fn is_literal(s: &str) -> bool {
-    s.starts_with("\"")
+pub fn parse_program(input: &mut Input, env: &Rc<RefCell<SymTable>>) -> Program {
+    let mut tokens = vec![];
+    while let Some(token) = input.next_token() {
+        tokens.push(token);
+    }
+    let makes = top_makes(&tokens);
+    let made = Made::new(&tokens, &makes);
+    let block = Rc::new(Block::new(tokens));
+
+    // The file's own table, so the session's names are left as they are.
+    // Functions are bound as their makes are passed, like when it runs
+    let global = env.borrow().get_global();
+    let scope = Rc::new(RefCell::new(SymTable::new(Some(Rc::clone(&global)), None)));
+
+    let mut program = Program {
+        block: Rc::clone(&block),
+        stmts: vec![],
+        errors: vec![],
+        deferred: None,
+        dir: None,
+    };
+    let mut start = 0;
+    let mut made_at = makes.iter().peekable();
+    while start < block.len() {
+        let mut deps = vec![];
+        let res = block.group(start, &scope, &mut deps);
+        if let Some(name) = made.find(&deps, &[]) {
+            let pos = block.tokens[start..]
+                .iter()
+                .find(|(token, _)| *token == Token::Name(name.to_owned()))
+                .and_then(|(_, pos)| pos.clone());
+            program.deferred = Some((name.to_owned(), pos));
+            break;
+        }
+        let end = match res {
+            Ok(Some(stmt)) => {
+                let end = stmt.end;
+                program.stmts.push(stmt);
+                end
+            }
+            Ok(None) => break,
+            Err((err, end)) => {
+                program.errors.push(err);
+                end
+            }
+        };
+        while let Some((_, name, val)) = made_at.next_if(|(i, ..)| *i < end) {
+            scope.borrow_mut().bind(name.clone(), val.clone());
+        }
+        start = end;
+    }
+
+    // Function bodies, a call's table sees the file's functions
+    for (_, name, val) in made_at {
+        scope.borrow_mut().bind(name.clone(), val.clone());
+    }
+    let call = Rc::new(RefCell::new(SymTable::new(Some(global), Some(scope))));
+    for stmt in &program.stmts {
+        if let At(
+            _,
+            box Make(_, box Value(ValType::List(_, ListType::Function(_, params, _, code)))),
+        ) = &stmt.expr
+        {
+            let mut start = 0;
+            while start < code.len() {
+                let mut deps = vec![];
+                let res = code.group(start, &call, &mut deps);
+                if made.find(&deps, params).is_some() {
+                    break;
+                }
+                start = match res {
+                    Ok(Some(stmt)) => stmt.end,
+                    Ok(None) => break,
+                    Err((err, end)) => {
+                        program.errors.push(err);
+                        end
+                    }
+                };
+            }
+        }
+    }
+    program
+        .errors
+        .sort_by_key(|err| err.pos.as_ref().map(|pos| (pos.line, pos.col)));
+    program
 }
-fn is_var(s: &str) -> bool {
-    s.starts_with(":")
+
+pub struct Stmt {
+    pub expr: Expr,
+    pub end: usize, // Where the next statement starts
+    deps: Deps,
+    // Bytecode of expr for the vm, as last compiled
+    pub code: RefCell<Option<Rc<Chunk>>>,
 }
 
-fn is_list(s: &str) -> bool {
-    s.starts_with("[")
+// Code kept in a list. A statement is parsed the first time it runs, and
+// reused as long as the names it was grouped with keep the same arities
+pub struct Block {
+    tokens: Vec<Spanned>,
+    // By the token each starts at
+    stmts: RefCell<Vec<Option<Rc<Stmt>>>>,
+    // Of a function's body, the names its calls have slots for, and the
+    // slots of its params
+    frame: Option<(Rc<Names>, Vec<usize>)>,
 }
 
-fn is_bool(s: &str) -> bool {
-    if s == "true" || s == "false" {
-        true
-    } else {
-        false
+impl Block {
+    pub fn new(tokens: Vec<Spanned>) -> Self {
+        Block {
+            stmts: RefCell::new(vec![None; tokens.len()]),
+            tokens,
+            frame: None,
+        }
     }
-}
 
-pub fn parse_sexpr(sexpr: &Sexpr) -> Expr {
-    match sexpr {
-        Atom(s) => {
-            if is_num(s) {
-                Value(ValType::Num(s.parse().unwrap()))
-            } else if is_literal(s) {
-                Value(ValType::Str(s[1..].to_string()))
-            } else if is_list(s) {
-                Value(solve_list(s))
-            } else if is_bool(s) {
-                Value(ValType::Boolean(s == "true"))
-            } else if is_var(s) {
-                Var(s[1..].to_string())
-            } else {
-                parse_error(&format!("Unregconized Atom {}", s))
+    // The body of a function taking params. Its calls have slots for the
+    // params, then for what the body may name: :names, "words, and the
+    // first word of each list, as for takes it
+    pub fn with_params(mut self, params: &[String]) -> Self {
+        let mut names = Names::new();
+        let params = params.iter().map(|param| names.add(param)).collect();
+        let mut first = false;
+        for (token, _) in &self.tokens {
+            match token {
+                Token::Var(name) => {
+                    names.add(name);
+                }
+                Token::Word(word) if is_name(&unescape(word)) => {
+                    names.add(&unescape(word));
+                }
+                Token::Name(name)
+                    if first && is_name(name) && !KEYWORD.contains_key(name.as_str()) =>
+                {
+                    names.add(name);
+                }
+                _ => (),
             }
+            first = *token == Token::LBracket;
         }
-        List(v) => {
-            if is_keyword(v.first()) {
-                match v.as_slice() {
-                    // 3 parameters
-                    [Atom(op), param1, param2, param3] => match op.as_str() {
-                        "if" => If(
-                            Box::new(parse_sexpr(param1)),
-                            Box::new(parse_sexpr(param2)),
-                            Box::new(parse_sexpr(param3)),
-                        ),
-                        _ => parse_error("Unrecognized List 3"),
-                    },
-                    // 2 parameters
-                    [Atom(op), param1, param2] => match op.as_str() {
-                        "make" => {
-                            Make(Box::new(parse_sexpr(param1)), Box::new(parse_sexpr(param2)))
-                        }
-                        "sentence" | "list" | "join" => Extend(
-                            op.to_string(),
-                            Box::new(parse_sexpr(param1)),
-                            Box::new(parse_sexpr(param2)),
-                        ),
-                        "add" | "sub" | "mul" | "div" | "mod" => Calc(
-                            op.to_string(),
-                            Box::new(parse_sexpr(param1)),
-                            Box::new(parse_sexpr(param2)),
-                        ),
-                        "eq" | "gt" | "lt" => Comp(
-                            op.to_string(),
-                            Box::new(parse_sexpr(param1)),
-                            Box::new(parse_sexpr(param2)),
-                        ),
-                        "and" | "or" => Logic(
-                            op.to_string(),
-                            Box::new(parse_sexpr(param1)),
-                            Box::new(parse_sexpr(param2)),
-                        ),
-                        _ => parse_error("Unrecognized List 2"),
-                    },
-                    // 1 parameters
-                    [Atom(op), param] => match op.as_str() {
-                        "print" => Print(Box::new(parse_sexpr(param))),
-                        "thing" => Thing(Box::new(parse_sexpr(param))),
-                        "erase" => Erase(Box::new(parse_sexpr(param))),
-                        "run" => Run(Box::new(parse_sexpr(param))),
-                        "save" => Save(Box::new(parse_sexpr(param))),
-                        "load" => Load(Box::new(parse_sexpr(param))),
-                        "not" => Logic(
-                            "not".to_string(),
-                            Box::new(parse_sexpr(param)),
-                            Box::new(Value(ValType::Boolean(true))),
-                        ),
-                        "isname" | "isnumber" | "isword" | "islist" | "isbool" | "isempty" => {
-                            Judge(op.to_string(), Box::new(parse_sexpr(param)))
-                        }
-                        "first" | "last" | "butfirst" | "butlast" => {
-                            Index(op.to_string(), Box::new(parse_sexpr(param)))
-                        }
-                        "return" => Return(Box::new(parse_sexpr(param))),
-                        "export" => Export(Box::new(parse_sexpr(param))),
-                        _ => parse_error("Unrecognized List 1"),
-                    },
-                    // no parameters
-                    [Atom(op)] => match op.as_str() {
-                        "nop" => Nop,
-                        "read" => Read,
-                        "exit" => Exit,
-                        "erall" => Erall,
-                        _ => parse_error("Unrecognized List 0"),
-                    },
-                    _ => parse_error("Invalid syntax!"),
+        self.frame = Some((Rc::new(names), params));
+        self
+    }
+
+    pub fn frame(&self) -> Option<&(Rc<Names>, Vec<usize>)> {
+        self.frame.as_ref()
+    }
+
+    // Code built at runtime, it has no source position
+    pub fn from_list(list: &VecDeque<ValType>) -> Self {
+        fn tokens(list: &VecDeque<ValType>, code: &mut Vec<Spanned>) {
+            for val in list {
+                match val {
+                    ValType::List(list, _) => {
+                        code.push((Token::LBracket, None));
+                        tokens(list, code);
+                        code.push((Token::RBracket, None));
+                    }
+                    val => code.push((Token::classify(&val.to_string()), None)),
                 }
-            } else if let Some(Atom(func_name)) = v.first() {
-                // Function
-                Function(
-                    func_name.to_owned(),
-                    v.iter().skip(1).map(|sexpr| parse_sexpr(sexpr)).collect(),
-                )
-            } else {
-                parse_error("parse_sepr error, no match")
             }
         }
+        let mut code = vec![];
+        tokens(list, &mut code);
+        Block::new(code)
+    }
+
+    // The code as written, with its comments
+    pub fn text(&self) -> String {
+        let mut text = String::new();
+        let mut last: Option<&Token> = None;
+        for (token, _) in &self.tokens {
+            match (last, token) {
+                (Some(Token::Comment(_)), _) => text.push('\n'),
+                (None, _) | (Some(Token::LBracket), _) | (_, Token::RBracket) => (),
+                _ => text.push(' '),
+            }
+            text.push_str(&token.to_string());
+            last = Some(token);
+        }
+        if let Some(Token::Comment(_)) = last {
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn len(&self) -> usize {
+        self.tokens.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn statement(
+        &self,
+        start: usize,
+        env: &Rc<RefCell<SymTable>>,
+    ) -> Result<Option<Rc<Stmt>>, MuaError> {
+        if let Some(Some(stmt)) = self.stmts.borrow().get(start) {
+            let env = env.borrow();
+            if stmt.deps.iter().all(|(name, n)| arity(name, &env) == *n) {
+                return Ok(Some(Rc::clone(stmt)));
+            }
+        }
+
+        self.group(start, env, &mut vec![]).map_err(|(err, _)| err)
+    }
+
+    // Group the statement at start, the names it looked up go to deps.
+    // On an error, where the tokens it took end is returned too
+    fn group(
+        &self,
+        start: usize,
+        env: &Rc<RefCell<SymTable>>,
+        deps: &mut Deps,
+    ) -> Result<Option<Rc<Stmt>>, (MuaError, usize)> {
+        let mut input = Input::tokens(&self.tokens[start..]);
+        let sexpr = read_sexpr(&mut input, Rc::clone(env), deps);
+        let end = self.tokens.len() - input.remaining();
+        let sexpr = match sexpr {
+            Ok(Some(sexpr)) => sexpr,
+            Ok(None) => return Ok(None),
+            Err(err) => return Err((err, end)),
+        };
+        let mut own = deps.clone();
+        own.sort();
+        own.dedup();
+        let stmt = Rc::new(Stmt {
+            expr: parse_sexpr(&sexpr).map_err(|err| (err, end))?,
+            end,
+            deps: own,
+            code: RefCell::new(None),
+        });
+        self.stmts.borrow_mut()[start] = Some(Rc::clone(&stmt));
+        Ok(Some(stmt))
+    }
+}
+
+// Blocks are told apart by their code only
+impl fmt::Debug for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Block").field(&self.tokens).finish()
     }
 }
 
-pub fn parse(input: &mut Input, env: Rc<RefCell<SymTable>>) -> Option<Expr> {
-    parse_string(input, Rc::clone(&env)).map(|sexpr| parse_sexpr(&sexpr))
+impl PartialEq for Block {
+    fn eq(&self, other: &Self) -> bool {
+        self.tokens == other.tokens
+    }
+}
+
+impl Eq for Block {}
+
+impl Hash for Block {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.tokens.hash(state);
+    }
 }
@@ -0,0 +1,721 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-09 14:02:37
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-09 14:02:37
+ * @Description: file information
+ * @Copyright: Copyright (c) 2022
+ */
+
+// Operations on values, shared by the tree interpreter and the vm
+
+use crate::error::{ErrorKind, MuaError};
+use crate::interp::{Files, Runtime};
+use crate::parser::{is_name, is_num, Block};
+use crate::syntax::*;
+use crate::{image, source};
+use crate::{vecdeque, Input};
+use ordered_float::OrderedFloat;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The state random starts from for a seed. xorshift never leaves 0, so
+// that seed takes another state
+pub fn seed_state(seed: u64) -> u64 {
+    if seed == 0 {
+        0x9e37_79b9_7f4a_7c15
+    } else {
+        seed
+    }
+}
+
+// A seed for runs which don't fix one
+pub fn clock_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+// xorshift64*, returns a float in [0, 1)
+fn random(rt: &mut Runtime) -> f64 {
+    let mut x = rt.random;
+    x ^= x >> 12;
+    x ^= x << 25;
+    x ^= x >> 27;
+    rt.random = x;
+    (x.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 11) as f64 / (1u64 << 53) as f64
+}
+
+pub fn interp_error<T>(kind: ErrorKind, content: impl Into<String>) -> Result<T, MuaError> {
+    Err(MuaError::new(kind, content))
+}
+
+pub fn num(val: ValType) -> Result<OrderedFloat<f64>, MuaError> {
+    val.try_into()
+}
+
+// A function literal is a closure over the env it's made in
+pub fn value(val: &ValType, env: &Rc<RefCell<SymTable>>) -> ValType {
+    let mut val = val.clone();
+    if let ValType::List(_, ListType::Function(context, _, _, _)) = &mut val {
+        *context = SymTable::capture(env);
+    }
+    val
+}
+
+pub fn thing(name: &str, env: &Rc<RefCell<SymTable>>) -> Result<ValType, MuaError> {
+    match env.borrow().lookup(name) {
+        Some(val) => Ok(val),
+        None => interp_error(
+            ErrorKind::UndefinedName,
+            format!("undefined name \"{}\"", name),
+        ),
+    }
+}
+
+// The name operand of make, thing and export
+pub fn name(val: ValType, op: &str) -> Result<String, MuaError> {
+    if let ValType::Str(name) = val {
+        Ok(name)
+    } else {
+        interp_error(
+            ErrorKind::TypeMismatch,
+            format!("{} expects a word as the name", op),
+        )
+    }
+}
+
+pub fn make(name: String, val: ValType, env: &Rc<RefCell<SymTable>>) -> ValType {
+    env.borrow_mut().bind(name, val.clone());
+    val
+}
+
+// The name to erase is taken as written, it's never evaluated
+pub fn erase(name: Option<&str>, env: &Rc<RefCell<SymTable>>) -> Result<ValType, MuaError> {
+    if let Some(n) = name {
+        if env.borrow_mut().unbind(n.to_string()).is_none() {
+            return interp_error(
+                ErrorKind::UndefinedName,
+                format!("undefined name \"{}\"", n),
+            );
+        }
+        Ok(ValType::Num(0.0.into()))
+    } else {
+        interp_error(ErrorKind::TypeMismatch, "erase expects a word as the name")
+    }
+}
+
+pub fn print(rt: &mut Runtime, val: ValType) -> Result<ValType, MuaError> {
+    writeln!(rt.output.out(), "{}", val)?;
+    Ok(val)
+}
+
+pub fn export(name: String, env: &Rc<RefCell<SymTable>>) -> Result<ValType, MuaError> {
+    match env.borrow_mut().export(name.clone()) {
+        Some(val) => Ok(val),
+        None => interp_error(
+            ErrorKind::UndefinedName,
+            format!("undefined local name \"{}\"", name),
+        ),
+    }
+}
+
+pub fn judge(op: JudgeOp, val: ValType, env: &Rc<RefCell<SymTable>>) -> ValType {
+    ValType::Boolean(match op {
+        JudgeOp::Name => env.borrow().exist_local(&val.to_string()),
+        JudgeOp::Number => is_num(val.to_string().as_str()),
+        JudgeOp::Word => val.is_string(),
+        JudgeOp::List => val.is_list(),
+        JudgeOp::Bool => val.is_bool(),
+        JudgeOp::Empty => val
+            .to_string()
+            .trim_matches(|c| c == '[' || c == ']')
+            .is_empty(),
+    })
+}
+
+pub fn index(op: IndexOp, val: ValType) -> Result<ValType, MuaError> {
+    if let ValType::List(mut list, _) = val {
+        if list.is_empty() {
+            return interp_error(
+                ErrorKind::TypeMismatch,
+                format!("{} of empty list", op.name()),
+            );
+        }
+        Ok(match op {
+            IndexOp::First => list.pop_front().unwrap(),
+            IndexOp::Last => list.pop_back().unwrap(),
+            IndexOp::ButFirst => {
+                list.pop_front();
+                ValType::List(list, ListType::Ordinary)
+            }
+            IndexOp::ButLast => {
+                list.pop_back();
+                ValType::List(list, ListType::Ordinary)
+            }
+        })
+    } else {
+        let str = val.to_string();
+        let mut chars = str.chars();
+        if str.is_empty() {
+            return interp_error(
+                ErrorKind::TypeMismatch,
+                format!("{} of empty word", op.name()),
+            );
+        }
+        Ok(match op {
+            IndexOp::First => ValType::Str(chars.next().unwrap().to_string()),
+            IndexOp::Last => ValType::Str(chars.next_back().unwrap().to_string()),
+            IndexOp::ButFirst => {
+                chars.next();
+                ValType::Str(chars.collect())
+            }
+            IndexOp::ButLast => {
+                chars.next_back();
+                ValType::Str(chars.collect())
+            }
+        })
+    }
+}
+
+pub fn math(rt: &mut Runtime, op: MathOp, v: OrderedFloat<f64>) -> ValType {
+    ValType::Num(match op {
+        MathOp::Random => (random(rt) * v.0).into(),
+        MathOp::Int => v.0.floor().into(),
+        MathOp::Sqrt => v.0.sqrt().into(),
+    })
+}
+
+pub fn calc(op: CalcOp, v1: OrderedFloat<f64>, v2: OrderedFloat<f64>) -> Result<ValType, MuaError> {
+    Ok(ValType::Num(match op {
+        CalcOp::Add => v1 + v2,
+        CalcOp::Sub => v1 - v2,
+        CalcOp::Mul => v1 * v2,
+        CalcOp::Div | CalcOp::Mod if v2.0 == 0.0 => {
+            return interp_error(ErrorKind::DivideByZero, format!("{} by zero", op.name()))
+        }
+        CalcOp::Div => v1 / v2,
+        CalcOp::Mod => v1 % v2,
+    }))
+}
+
+pub fn comp(op: CompOp, v1: ValType, v2: ValType) -> Result<ValType, MuaError> {
+    if v1.is_num() && v2.is_num() {
+        // Integer compare
+        let v1 = num(v1)?;
+        let v2 = num(v2)?;
+        Ok(ValType::Boolean(match op {
+            CompOp::Eq => v1 == v2,
+            CompOp::Gt => v1 > v2,
+            CompOp::Lt => v1 < v2,
+        }))
+    } else {
+        // String compare
+        let v1 = v1.to_string();
+        let v2 = v2.to_string();
+        Ok(ValType::Boolean(match op {
+            CompOp::Eq => v1 == v2,
+            CompOp::Gt => v1 > v2,
+            CompOp::Lt => v1 < v2,
+        }))
+    }
+}
+
+pub fn logic(op: LogicOp, v1: ValType, v2: ValType) -> Result<ValType, MuaError> {
+    if let (ValType::Boolean(b1), ValType::Boolean(b2)) = (v1, v2) {
+        Ok(ValType::Boolean(match op {
+            LogicOp::And => b1 && b2,
+            LogicOp::Or => b1 || b2,
+            LogicOp::Not => !b1,
+        }))
+    } else {
+        interp_error(
+            ErrorKind::TypeMismatch,
+            format!("{} expects bool", op.name()),
+        )
+    }
+}
+
+pub fn extend(rt: &Runtime, op: ExtendOp, v1: ValType, v2: ValType) -> Result<ValType, MuaError> {
+    // Numbers and bools are words, written as print does
+    if op == ExtendOp::Word {
+        let is_word = |val: &ValType| val.is_string() || val.is_num() || val.is_bool();
+        if !is_word(&v1) || !is_word(&v2) {
+            return interp_error(ErrorKind::TypeMismatch, "word expects word, number or bool");
+        }
+        return check_size(rt, ValType::Str(format!("{}{}", v1, v2)));
+    }
+
+    // A list's size is that of what it's built of, known before it's built
+    let size = match rt.limits.size {
+        Some(limit) => {
+            let size = match op {
+                ExtendOp::Sentence => list_size(&v1) + list_size(&v2),
+                ExtendOp::List => 2 + size(&v1) + size(&v2),
+                _ => list_size(&v1) + 1 + size(&v2),
+            };
+            if size > limit {
+                return size_error(limit);
+            }
+            Some(size)
+        }
+        None => None,
+    };
+    let list = match op {
+        ExtendOp::Sentence => {
+            let mut list = v1.into_list();
+            list.extend(v2.into_list());
+            list
+        }
+        ExtendOp::List => vecdeque![v1, v2].into(),
+        _ => {
+            let mut list = v1.into_list();
+            list.push_back(v2);
+            list
+        }
+    };
+    if let Some(size) = size {
+        list.set_size(size);
+    }
+    Ok(ValType::List(list, ListType::Ordinary))
+}
+
+pub fn condition(val: ValType, op: &str) -> Result<bool, MuaError> {
+    if let ValType::Boolean(b) = val {
+        Ok(b)
+    } else {
+        interp_error(
+            ErrorKind::TypeMismatch,
+            format!("{} expects bool as condition", op),
+        )
+    }
+}
+
+// A list computed at runtime, to be run
+pub fn code(val: ValType, op: &str) -> Result<Block, MuaError> {
+    if let ValType::List(list, _) = val {
+        Ok(Block::from_list(&list))
+    } else {
+        interp_error(ErrorKind::TypeMismatch, format!("{} expects a list", op))
+    }
+}
+
+// Out of any function, return only stops the list. A break or continue
+// goes on to its loop
+pub fn leave_list(rt: &Runtime, res: ValType) -> ValType {
+    if rt.stack.is_empty() && matches!(res, ValType::Retv(_) | ValType::Tail(_)) {
+        res.get_ret_value()
+    } else {
+        res
+    }
+}
+
+// Where no loop can be, a function's end or the top level, a break or
+// continue has gone too far
+pub fn outside_loop(res: ValType) -> Result<ValType, MuaError> {
+    match res {
+        ValType::Leave(_) => {
+            interp_error(ErrorKind::FlowError, format!("{} is not in a loop", res))
+        }
+        res => Ok(res),
+    }
+}
+
+// What a loop does with what its body gave: None goes on, else the loop
+// is left with the value
+pub fn loop_exit(res: ValType) -> Option<ValType> {
+    match res {
+        ValType::Leave(Flow::Break) => Some(ValType::Null),
+        ValType::Retv(_) | ValType::Tail(_) => Some(res),
+        _ => None,
+    }
+}
+
+// Rounds of repeat, none for a negative count
+pub fn times(val: ValType) -> Result<u64, MuaError> {
+    let n = num(val)?.0;
+    Ok(if n > 0.0 { n as u64 } else { 0 })
+}
+
+// Values a for loop's name takes, from start by step as far as end
+#[derive(Debug, Clone)]
+pub struct Steps {
+    start: f64,
+    end: f64,
+    step: f64,
+    taken: u64,
+}
+
+impl Iterator for Steps {
+    type Item = f64;
+
+    fn next(&mut self) -> Option<f64> {
+        // Computed from start each time, so steps don't add up errors
+        let n = self.start + self.taken as f64 * self.step;
+        if (self.step > 0.0 && n > self.end) || (self.step < 0.0 && n < self.end) {
+            return None;
+        }
+        self.taken += 1;
+        Some(n)
+    }
+}
+
+// [name start end step] of for. Bounds are numbers or :names, the step
+// is 1 or -1 towards end if left out
+pub fn for_range(
+    control: ValType,
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<(String, Steps), MuaError> {
+    let usage = || {
+        interp_error(
+            ErrorKind::TypeMismatch,
+            "for expects [name start end] or [name start end step]",
+        )
+    };
+    let list = match control {
+        ValType::List(list, ListType::Ordinary) if list.len() == 3 || list.len() == 4 => list,
+        _ => return usage(),
+    };
+    let var = match &list[0] {
+        ValType::Str(name) if is_name(name) => name.clone(),
+        _ => return usage(),
+    };
+    let mut bounds = vec![];
+    for val in list.iter().skip(1) {
+        let val = match val {
+            ValType::Str(s) if s.starts_with(':') => thing(&s[1..], env)?,
+            val => val.clone(),
+        };
+        bounds.push(num(val)?.0);
+    }
+    // It would never end, no comparison with NaN holds
+    if bounds.iter().any(|n| !n.is_finite()) {
+        return interp_error(ErrorKind::ArgumentError, "for expects finite numbers");
+    }
+    let (start, end) = (bounds[0], bounds[1]);
+    let step = match bounds.get(2) {
+        Some(&step) => step,
+        None if end < start => -1.0,
+        None => 1.0,
+    };
+    if step == 0.0 {
+        return interp_error(ErrorKind::ArgumentError, "for expects a step other than 0");
+    }
+    Ok((
+        var,
+        Steps {
+            start,
+            end,
+            step,
+            taken: 0,
+        },
+    ))
+}
+
+// What foreach goes through, a list's items or a word's characters
+pub fn items(val: ValType) -> Result<VecDeque<ValType>, MuaError> {
+    match val {
+        ValType::List(list, ListType::Ordinary) => Ok(list.into_inner()),
+        ValType::Str(s) => Ok(s.chars().map(|c| ValType::Str(c.to_string())).collect()),
+        _ => interp_error(ErrorKind::TypeMismatch, "foreach expects a list or word"),
+    }
+}
+
+// The function foreach calls, a function or the name of one taking one
+// argument. The name is for backtraces
+pub fn each_callee(
+    val: ValType,
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<(String, Callee), MuaError> {
+    match val {
+        ValType::Str(name) => {
+            let callee = function(&name, 1, env)?;
+            Ok((name, callee))
+        }
+        val => match val.list_is_func() {
+            Some(1) => Ok(("foreach".to_string(), Callee::Mua(val.closure().unwrap()))),
+            Some(arity) => arity_error("the function of foreach", arity as usize, 1),
+            None => interp_error(ErrorKind::TypeMismatch, "foreach expects a function"),
+        },
+    }
+}
+
+pub fn read(rt: &mut Runtime) -> Result<ValType, MuaError> {
+    match rt.data.next_word() {
+        Some(str) => check_size(rt, ValType::Str(str)),
+        None => interp_error(ErrorKind::IoError, "read reaches end of input"),
+    }
+}
+
+pub fn read_list(rt: &mut Runtime) -> Result<ValType, MuaError> {
+    match rt.data.next_line() {
+        Some(words) => check_size(
+            rt,
+            ValType::List(
+                words.into_iter().map(ValType::Str).collect(),
+                ListType::Ordinary,
+            ),
+        ),
+        None => interp_error(ErrorKind::IoError, "readlist reaches end of input"),
+    }
+}
+
+// Where the file save or load names is, if rt.files lets them at it
+fn file_path(rt: &Runtime, name: &str) -> Result<PathBuf, MuaError> {
+    let dir = match &rt.files {
+        Files::Any => {
+            return Ok(match &rt.dir {
+                Some(dir) => dir.join(name),
+                None => PathBuf::from(name),
+            })
+        }
+        Files::Denied => {
+            return interp_error(
+                ErrorKind::AccessDenied,
+                format!("no file access for \"{}\"", name),
+            )
+        }
+        Files::Within(dir) => dir,
+    };
+    let outside = || {
+        interp_error(
+            ErrorKind::AccessDenied,
+            format!("\"{}\" is outside {}", name, dir.display()),
+        )
+    };
+    let path = Path::new(name);
+    let relative = path
+        .components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    if name.is_empty() || !relative {
+        return outside();
+    }
+
+    // No .. left, but a link may still lead out
+    let path = dir.join(path);
+    let resolved = match path.canonicalize() {
+        Ok(resolved) => resolved,
+        Err(_) => path.parent().unwrap_or(dir).canonicalize()?,
+    };
+    if !resolved.starts_with(dir.canonicalize()?) {
+        return outside();
+    }
+    Ok(path)
+}
+
+pub fn save(
+    rt: &Runtime,
+    filename: ValType,
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    let filename = filename.to_string();
+    let mut file = File::create(file_path(rt, &filename)?)?;
+    file.write_all(source::program(&env.borrow()).as_bytes())?;
+    Ok(ValType::Str(filename))
+}
+
+// The file load runs as input
+pub fn load(rt: &Runtime, filename: ValType) -> Result<(Input<'static>, PathBuf), MuaError> {
+    let path = file_path(rt, &filename.to_string())?;
+    Ok((Input::file(&path.to_string_lossy())?, path))
+}
+
+// The global names, whatever table it's called in
+pub fn save_image(
+    rt: &Runtime,
+    filename: ValType,
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    let filename = filename.to_string();
+    let global = env.borrow().get_global();
+    let bytes = image::write(&global.borrow());
+    fs::write(file_path(rt, &filename)?, bytes)?;
+    Ok(ValType::Str(filename))
+}
+
+// The global names become those of the image, all or none
+pub fn load_image(
+    rt: &Runtime,
+    filename: ValType,
+    env: &Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    let bytes = fs::read(file_path(rt, &filename.to_string())?)?;
+    let global = env.borrow().get_global();
+    image::read(&bytes, &global)?;
+    Ok(ValType::Boolean(true))
+}
+
+// Run f with relative names taken from where the script at path is
+pub fn in_dir<T>(rt: &mut Runtime, path: &Path, f: impl FnOnce(&mut Runtime) -> T) -> T {
+    let dir = path.parent().map(Path::to_path_buf);
+    let outer = std::mem::replace(&mut rt.dir, dir);
+    let res = f(rt);
+    rt.dir = outer;
+    res
+}
+
+pub fn erall(env: &Rc<RefCell<SymTable>>) -> ValType {
+    env.borrow_mut().clear_all();
+    ValType::Boolean(true)
+}
+
+// What a call runs, a MUA function or a host primitive
+#[derive(Clone)]
+pub enum Callee {
+    Mua(Closure),
+    Host(Rc<Host>),
+}
+
+// Look up the function of a call, before its arguments are evaluated.
+// Host primitives come first, as they group arguments like keywords
+pub fn function(op: &str, argc: usize, env: &Rc<RefCell<SymTable>>) -> Result<Callee, MuaError> {
+    if let Some(host) = env.borrow().host(op) {
+        return if host.arity == argc {
+            Ok(Callee::Host(host))
+        } else {
+            arity_error(op, host.arity, argc)
+        };
+    }
+
+    // println!("Debug - run func: {}", op);
+    match env.borrow().lookup_with(op, |func| callee(op, argc, func)) {
+        Some(callee) => callee,
+        None => undefined_function(op),
+    }
+}
+
+// The function op names, if it is one taking argc arguments
+pub fn callee(op: &str, argc: usize, func: &ValType) -> Result<Callee, MuaError> {
+    match (func.list_is_func(), func.closure()) {
+        (Some(arity), _) if arity as usize != argc => arity_error(op, arity as usize, argc),
+        (_, Some(closure)) => Ok(Callee::Mua(closure)),
+        _ => interp_error(
+            ErrorKind::TypeMismatch,
+            format!("\"{}\" is not a function", op),
+        ),
+    }
+}
+
+pub fn undefined_function<T>(op: &str) -> Result<T, MuaError> {
+    interp_error(
+        ErrorKind::UndefinedName,
+        format!("undefined function \"{}\"", op),
+    )
+}
+
+fn arity_error<T>(op: &str, arity: usize, argc: usize) -> Result<T, MuaError> {
+    interp_error(
+        ErrorKind::ArityError,
+        format!("{} expects {} arguments, found {}", op, arity, argc),
+    )
+}
+
+pub fn check_depth(rt: &Runtime) -> Result<(), MuaError> {
+    if rt.stack.len() >= rt.limits.depth {
+        interp_error(
+            ErrorKind::RecursionLimit,
+            format!("call depth exceeds {}", rt.limits.depth),
+        )
+    } else {
+        Ok(())
+    }
+}
+
+// Native stack kept free under the deepest MUA code, for the operations
+// it runs
+const STACK_RED_ZONE: usize = 256 * 1024;
+
+// The tree engine nests on the native stack, as does load. However large
+// the thread's stack is, running out of it is a MUA error
+pub fn check_stack() -> Result<(), MuaError> {
+    match stacker::remaining_stack() {
+        Some(left) if left < STACK_RED_ZONE => interp_error(
+            ErrorKind::RecursionLimit,
+            "code nests deeper than the native stack holds",
+        ),
+        _ => Ok(()),
+    }
+}
+
+// Take a step out of the budget
+pub fn tick(rt: &mut Runtime) -> Result<(), MuaError> {
+    if rt.interrupt.load(Ordering::Relaxed) {
+        rt.interrupt.store(false, Ordering::Relaxed);
+        return interp_error(ErrorKind::Interrupted, "run stopped");
+    }
+    rt.steps += 1;
+    match rt.limits.steps {
+        Some(steps) if rt.steps > steps => {
+            interp_error(ErrorKind::StepLimit, format!("run exceeds {} steps", steps))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Measured once a list, it keeps its size
+fn size(val: &ValType) -> usize {
+    match val {
+        ValType::Str(s) => s.len(),
+        ValType::List(list, _) => list.size().unwrap_or_else(|| {
+            let size = list.len() + list.iter().map(size).sum::<usize>();
+            list.set_size(size);
+            size
+        }),
+        _ => 1,
+    }
+}
+
+// Of val as sentence and join take it, a list of itself if it isn't one
+fn list_size(val: &ValType) -> usize {
+    match val {
+        ValType::List(..) => size(val),
+        val => 1 + size(val),
+    }
+}
+
+fn size_error<T>(limit: usize) -> Result<T, MuaError> {
+    interp_error(
+        ErrorKind::SizeLimit,
+        format!("value size exceeds {}", limit),
+    )
+}
+
+// A value just built must fit in limits.size
+fn check_size(rt: &Runtime, val: ValType) -> Result<ValType, MuaError> {
+    match rt.limits.size {
+        Some(limit) if size(&val) > limit => size_error(limit),
+        _ => Ok(val),
+    }
+}
+
+// The body of a function and the env it runs in
+pub fn bind(
+    func: Closure,
+    args: &[ValType],
+    global: &Rc<RefCell<SymTable>>,
+) -> (Rc<Block>, Rc<RefCell<SymTable>>) {
+    let (names, params) = func.code.frame().expect("only functions are called");
+    let mut cenv = SymTable::with_names(
+        Some(Rc::clone(global)),
+        func.context.map(|context| context.0),
+        Rc::clone(names),
+    );
+    for (&slot, param) in params.iter().zip(args.iter()) {
+        cenv.set(slot, param.clone());
+    }
+    (func.code, Rc::new(RefCell::new(cenv)))
+}
+
+// The run stops as it would on an error, it's up to the host whether
+// the process goes too
+pub fn bye<T>() -> Result<T, MuaError> {
+    interp_error(ErrorKind::Exit, "exit")
+}
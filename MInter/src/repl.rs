@@ -0,0 +1,454 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-15 09:30:12
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-15 09:30:12
+ * @Description: The interactive loop, with a line editor on terminals
+ * @Copyright: Copyright (c) 2022
+ */
+use crate::report;
+use ansi_term::Color;
+use minter::{Frame, Input, Interpreter, MuaError, Stream, ValType};
+use rustyline::completion::{Completer, FilenameCompleter};
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::Editor;
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::io::{self, BufRead, IsTerminal, Read};
+use std::path::PathBuf;
+use std::process::exit;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+const PROMPT: &str = "User>";
+// While a statement waits for more words or a `]`
+const MORE: &str = "....>";
+
+// Lines typed at a terminal, with editing and history
+struct Console {
+    editor: Editor<Assist, DefaultHistory>,
+    history: Option<PathBuf>,
+    // Whether the next line starts a statement
+    fresh: Rc<Cell<bool>>,
+    // Set on Ctrl-C, the line then ends input for the statement read
+    cancelled: Rc<Cell<bool>>,
+    // Ended with Ctrl-D, nothing is asked for after
+    ended: bool,
+    line: String,
+    at: usize,
+}
+
+impl Console {
+    fn new(
+        fresh: Rc<Cell<bool>>,
+        cancelled: Rc<Cell<bool>>,
+        names: Rc<RefCell<Names>>,
+    ) -> rustyline::Result<Self> {
+        let mut editor = Editor::new()?;
+        editor.set_helper(Some(Assist {
+            names,
+            files: FilenameCompleter::new(),
+        }));
+        let history = std::env::var_os("HOME")
+            .or_else(|| std::env::var_os("USERPROFILE"))
+            .map(|home| PathBuf::from(home).join(".minter_history"));
+        if let Some(path) = &history {
+            // There's none the first time
+            let _ = editor.load_history(path);
+        }
+        Ok(Console {
+            editor,
+            history,
+            fresh,
+            cancelled,
+            ended: false,
+            line: String::new(),
+            at: 0,
+        })
+    }
+}
+
+impl Read for Console {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.fill_buf()?.read(buf)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for Console {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.at == self.line.len() && !self.ended {
+            self.line.clear();
+            self.at = 0;
+            let prompt = if self.fresh.replace(false) {
+                PROMPT
+            } else {
+                MORE
+            };
+            match self.editor.readline(prompt) {
+                Ok(line) => {
+                    if !line.trim().is_empty() {
+                        let _ = self.editor.add_history_entry(line.as_str());
+                        if let Some(path) = &self.history {
+                            let _ = self.editor.append_history(path);
+                        }
+                    }
+                    self.line = line + "\n";
+                }
+                Err(ReadlineError::Eof) => self.ended = true,
+                Err(ReadlineError::Interrupted) => self.cancelled.set(true),
+                Err(err) => return Err(io::Error::other(err)),
+            }
+        }
+        Ok(&self.line.as_bytes()[self.at..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.at += amt;
+    }
+}
+
+// What the editor offers on tab, taken from the session before each
+// statement
+#[derive(Default)]
+struct Names {
+    operations: Vec<String>,
+    vars: Vec<String>,
+}
+
+impl Names {
+    fn of(interp: &Interpreter) -> Self {
+        let mut vars: Vec<_> = interp
+            .globals()
+            .get_keys_values()
+            .map(|(name, _)| name.clone())
+            .collect();
+        vars.sort();
+        Names {
+            operations: interp.operations(),
+            vars,
+        }
+    }
+}
+
+// Colors the prompts and completes names
+struct Assist {
+    names: Rc<RefCell<Names>>,
+    files: FilenameCompleter,
+}
+
+impl Highlighter for Assist {
+    fn highlight_prompt<'b, 's: 'b, 'p: 'b>(
+        &'s self,
+        prompt: &'p str,
+        _default: bool,
+    ) -> Cow<'b, str> {
+        let color = if prompt == PROMPT {
+            Color::Green
+        } else {
+            Color::Yellow
+        };
+        Cow::Owned(color.paint(prompt).to_string())
+    }
+}
+
+impl Completer for Assist {
+    type Candidate = String;
+
+    // What's completed is told by how the word starts: a command at the
+    // start of the line, a name after : or ", else something to call.
+    // ,load takes a file. A word completed is ended with a space
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        if line.trim_start().starts_with(",load ") {
+            let (start, pairs) = self.files.complete(line, pos, ctx)?;
+            return Ok((
+                start,
+                pairs.into_iter().map(|pair| pair.replacement).collect(),
+            ));
+        }
+        let start = line[..pos]
+            .rfind(|c: char| c.is_whitespace() || c == '[' || c == ']')
+            .map_or(0, |at| at + 1);
+        let word = &line[start..pos];
+        let names = self.names.borrow();
+        let (prefix, candidates): (&str, Vec<&str>) = match word.chars().next() {
+            Some(',') if line[..start].trim().is_empty() => ("", COMMANDS.to_vec()),
+            Some(c @ (':' | '"')) => (
+                if c == ':' { ":" } else { "\"" },
+                names.vars.iter().map(String::as_str).collect(),
+            ),
+            // A number is expected
+            Some(c) if c.is_ascii_digit() || c == '-' || c == '.' => ("", vec![]),
+            _ => (
+                "",
+                names
+                    .operations
+                    .iter()
+                    .map(String::as_str)
+                    .chain(["true", "false"])
+                    .collect(),
+            ),
+        };
+        let rest = &word[prefix.len()..];
+        let found = candidates
+            .into_iter()
+            .filter(|name| name.starts_with(rest))
+            .map(|name| format!("{}{} ", prefix, name))
+            .collect();
+        Ok((start, found))
+    }
+}
+
+impl Hinter for Assist {
+    type Hint = String;
+}
+
+impl Validator for Assist {}
+
+impl rustyline::Helper for Assist {}
+
+// What the session keeps between statements
+struct Session {
+    backtrace: Vec<Frame>,
+    // Whether values of statements are written
+    echo: bool,
+}
+
+// Statements whose value is seen already, or is only what they were given
+const QUIET: [&str; 11] = [
+    "print",
+    "make",
+    "erase",
+    "erall",
+    "export",
+    "save",
+    "load",
+    "saveimage",
+    "loadimage",
+    "nop",
+    "exit",
+];
+
+pub fn run(mut interp: Interpreter) -> ! {
+    let fresh = Rc::new(Cell::new(true));
+    let cancelled = Rc::new(Cell::new(false));
+    let names = Rc::new(RefCell::new(Names::default()));
+    let console = if io::stdin().is_terminal() {
+        Console::new(Rc::clone(&fresh), Rc::clone(&cancelled), Rc::clone(&names)).ok()
+    } else {
+        None
+    };
+    let editing = console.is_some();
+    let mut input = match console {
+        Some(console) => Input::named(console, "<stdin>"),
+        None => Input::console(),
+    };
+    if editing {
+        // Ctrl-C at the prompt is the editor's, while code runs it stops it
+        let interrupt = interp.interrupt();
+        let _ = ctrlc::set_handler(move || interrupt.store(true, Ordering::Relaxed));
+    }
+
+    let mut session = Session {
+        backtrace: vec![],
+        echo: editing,
+    };
+    loop {
+        // Piped lines get the prompt written before them
+        if input.at_line_end() {
+            fresh.set(true);
+            if editing {
+                names.replace(Names::of(&interp));
+            } else {
+                let out = interp.output();
+                let prompt = if out.styled(Stream::Out) {
+                    Color::Green.paint(PROMPT).to_string()
+                } else {
+                    PROMPT.to_string()
+                };
+                write!(out.out(), "{}", prompt)
+                    .and_then(|_| out.out().flush())
+                    .expect("Fatal error! Stdout flush fails!");
+            }
+        }
+
+        let head = input.peek_word();
+        // Ctrl-C at the prompt drops what's typed of the statement
+        if cancelled.take() {
+            input.clear();
+            continue;
+        }
+
+        // REPL commands start with ',', which no MUA word does
+        if let Some(command) = head.as_deref().filter(|word| word.starts_with(',')) {
+            input.next_word();
+            if let Err(err) = run_command(&mut interp, &mut session, command, &mut input) {
+                report(&mut interp, &err);
+                session.backtrace = err.backtrace;
+            }
+            input.clear();
+            continue;
+        }
+
+        let res = interp.eval_next(&mut input);
+        if cancelled.take() {
+            input.clear();
+            continue;
+        }
+        match res {
+            Ok(Some(val)) => {
+                let quiet = head.as_deref().is_some_and(|head| QUIET.contains(&head));
+                if session.echo && !quiet && val != ValType::Null {
+                    print_lines(&mut interp, &[val.to_origin()])
+                        .expect("Fatal error! Stdout write fails!");
+                }
+            }
+            Ok(None) => exit(0),
+            Err(err) => {
+                // Keep the session, but drop the rest of the broken line
+                report(&mut interp, &err);
+                input.clear();
+                session.backtrace = err.backtrace;
+            }
+        }
+    }
+}
+
+const COMMANDS: [&str; 10] = [
+    ",vars",
+    ",funcs",
+    ",show",
+    ",reset",
+    ",load",
+    ",time",
+    ",ast",
+    ",echo",
+    ",backtrace",
+    ",bt",
+];
+
+// What a command takes is on the same line as it
+fn expects(interp: &mut Interpreter, input: &Input, command: &str, what: &str) -> bool {
+    let missing = input.at_line_end();
+    if missing {
+        note(interp, &format!("{} expects {}", command, what));
+    }
+    !missing
+}
+
+fn note(interp: &mut Interpreter, msg: &str) {
+    writeln!(interp.output().err(), "{}", msg).expect("Fatal error! Stderr write fails!");
+}
+
+fn run_command(
+    interp: &mut Interpreter,
+    session: &mut Session,
+    command: &str,
+    input: &mut Input,
+) -> Result<(), MuaError> {
+    match command {
+        ",vars" => {
+            let globals = interp.globals();
+            let mut vars: Vec<_> = globals
+                .get_keys_values()
+                .filter(|(_, val)| val.list_is_func().is_none())
+                .map(|(name, val)| format!("{} = {}", name, literal(val)))
+                .collect();
+            drop(globals);
+            vars.sort();
+            print_lines(interp, &vars)
+        }
+        ",funcs" => {
+            let globals = interp.globals();
+            let mut funcs: Vec<_> = globals
+                .functions()
+                .map(|(name, arity)| format!("{}/{}", name, arity))
+                .collect();
+            drop(globals);
+            funcs.sort();
+            print_lines(interp, &funcs)
+        }
+        ",show" if expects(interp, input, command, "a name") => {
+            let name = input.next_word().unwrap_or_default();
+            let source = interp.source(&name)?;
+            print_lines(interp, &[source.trim_end().to_string()])
+        }
+        ",reset" => {
+            interp.reset();
+            session.backtrace.clear();
+            Ok(())
+        }
+        ",load" if expects(interp, input, command, "a file") => {
+            // Like a file on the command line, nothing runs on a syntax error
+            let path = input.next_word().unwrap_or_default();
+            let program = interp.parse_file(&path)?;
+            match program.errors().split_last() {
+                Some((last, errors)) => {
+                    errors.iter().for_each(|err| report(interp, err));
+                    Err(last.clone())
+                }
+                None => interp.run_program(&program).map(|_| ()),
+            }
+        }
+        ",time" if expects(interp, input, command, "a statement") => {
+            let start = Instant::now();
+            let res = interp.eval_next(input);
+            let elapsed = start.elapsed();
+            res?;
+            print_lines(interp, &[format!("time: {:.3?}", elapsed)])
+        }
+        ",ast" if expects(interp, input, command, "a statement") => {
+            match interp.parse_next(input)? {
+                Some(ast) => print_lines(interp, &[ast]),
+                None => Ok(()),
+            }
+        }
+        ",echo" => {
+            session.echo = !session.echo;
+            let state = if session.echo { "on" } else { "off" };
+            print_lines(interp, &[format!("echo {}", state)])
+        }
+        ",backtrace" | ",bt" => interp.report_backtrace(&session.backtrace),
+        // Their argument is missing, which was told
+        ",show" | ",load" | ",time" | ",ast" => Ok(()),
+        _ => {
+            note(
+                interp,
+                &format!(
+                    "unknown command {}, there are {}",
+                    command,
+                    COMMANDS.join(" ")
+                ),
+            );
+            Ok(())
+        }
+    }
+}
+
+fn print_lines(interp: &mut Interpreter, lines: &[String]) -> Result<(), MuaError> {
+    let out = interp.output().out();
+    for line in lines {
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+// As it's written in code
+fn literal(val: &ValType) -> String {
+    match val {
+        ValType::Str(s) => format!("\"{}", s),
+        ValType::List(..) => format!("[{}]", val),
+        val => val.to_string(),
+    }
+}
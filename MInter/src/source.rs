@@ -0,0 +1,203 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-13 16:40:05
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-13 16:40:05
+ * @Description: Values written back as code, for save
+ * @Copyright: Copyright (c) 2022
+ */
+
+use crate::parser::{escape, is_function, Block};
+use crate::syntax::{vec2str, ListType, SymTable, ValType};
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::rc::Rc;
+
+// Statements making the names of table again, with the same values.
+// What has no literal is built by code, through helper names erased
+// at the end
+pub fn program(table: &SymTable) -> String {
+    let mut vars: Vec<_> = table.get_keys_values().collect();
+    vars.sort_by_key(|(name, _)| *name);
+    statements(vars)
+}
+
+// The statements making only name, helpers erased the same
+pub fn definition(name: &str, val: &ValType) -> String {
+    statements(vec![(&name.to_string(), val)])
+}
+
+fn statements(vars: Vec<(&String, &ValType)>) -> String {
+    let mut taken = HashSet::new();
+    for (name, val) in &vars {
+        taken.insert(name.to_string());
+        names(val, &mut taken);
+    }
+    let mut source = Source {
+        stmts: vec![],
+        temps: vec![],
+        taken,
+    };
+    for (name, val) in vars {
+        let expr = source.expr(val);
+        source.stmts.push(format!("make {} {}", word(name), expr));
+    }
+    for temp in std::mem::take(&mut source.temps) {
+        source.stmts.push(format!("erase \"{}", temp));
+    }
+
+    let mut program = source.stmts.join("\n");
+    program.push('\n');
+    program
+}
+
+// Names a helper must not shadow, in closures too
+fn names(val: &ValType, taken: &mut HashSet<String>) {
+    if let ValType::List(list, kind) = val {
+        list.iter().for_each(|val| names(val, taken));
+        if let ListType::Function(Some(context), ..) = kind {
+            let mut table = Some(context.0.clone());
+            while let Some(t) = table {
+                for (name, val) in t.borrow().get_keys_values() {
+                    taken.insert(name.to_string());
+                    names(val, taken);
+                }
+                table = t.borrow().get_context();
+            }
+        }
+    }
+}
+
+struct Source {
+    stmts: Vec<String>,
+    temps: Vec<String>,
+    taken: HashSet<String>,
+}
+
+impl Source {
+    // An expression evaluating to val at the top level
+    fn expr(&mut self, val: &ValType) -> String {
+        match val {
+            ValType::Num(n) => number(n.0),
+            ValType::Str(s) => word(s),
+            ValType::Boolean(b) => b.to_string(),
+            ValType::List(_, ListType::Function(Some(context), params, _, code)) => {
+                self.closure(function(params, code), context.0.clone())
+            }
+            ValType::List(_, ListType::Function(None, params, _, code)) => function(params, code),
+            ValType::List(list, ListType::Ordinary) => {
+                if plain(list) && !is_function(list) {
+                    vec2str(list)
+                } else {
+                    // join join [] e1 e2 ...
+                    let mut expr = "join ".repeat(list.len());
+                    expr.push_str("[]");
+                    for val in list {
+                        expr.push(' ');
+                        expr.push_str(&self.expr(val));
+                    }
+                    expr
+                }
+            }
+            ValType::Null | ValType::Retv(_) | ValType::Tail(_) | ValType::Leave(_) => {
+                "nop".to_string()
+            }
+        }
+    }
+
+    // A function made in a function is remade by calling functions which
+    // make the same locals, one for each table it's made in:
+    //   make "_save1 [[] [make "x 1 return [[] [make "y 2 return [[z] [...]]]]]]
+    //   make "_save1 _save1
+    //   make "_save1 _save1
+    fn closure(&mut self, function: String, context: Rc<RefCell<SymTable>>) -> String {
+        let mut maker = function;
+        let mut levels = 0;
+        let mut table = Some(context);
+        while let Some(t) = table {
+            let mut vars: Vec<_> = t
+                .borrow()
+                .get_keys_values()
+                .map(|(name, val)| (name.clone(), val.clone()))
+                .collect();
+            vars.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+            let mut body = String::new();
+            for (name, val) in vars {
+                let name = self.inline(&ValType::Str(name));
+                let val = self.inline(&val);
+                body.push_str(&format!("make {} {} ", name, val));
+            }
+            maker = format!("[[] [{}return {}]]", body, maker);
+            levels += 1;
+            table = t.borrow().get_context();
+        }
+
+        let temp = self.temp();
+        self.stmts.push(format!("make \"{} {}", temp, maker));
+        for _ in 0..levels {
+            self.stmts.push(format!("make \"{} {}", temp, temp));
+        }
+        format!(":{}", temp)
+    }
+
+    // An expression for val which may be put in a list literal, anything
+    // else is made ahead in a helper name
+    fn inline(&mut self, val: &ValType) -> String {
+        match val {
+            ValType::Str(s) if !s.contains(['[', ']']) => word(s),
+            ValType::Num(_) | ValType::Boolean(_) | ValType::Null => self.expr(val),
+            _ => {
+                let expr = self.expr(val);
+                let temp = self.temp();
+                self.stmts.push(format!("make \"{} {}", temp, expr));
+                format!(":{}", temp)
+            }
+        }
+    }
+
+    fn temp(&mut self) -> String {
+        let mut n = self.temps.len() + 1;
+        while self.taken.contains(&format!("_save{}", n)) {
+            n += 1;
+        }
+        let temp = format!("_save{}", n);
+        self.taken.insert(temp.clone());
+        self.temps.push(temp.clone());
+        temp
+    }
+}
+
+// Written from its code rather than its list, so comments are kept
+fn function(params: &[String], code: &Block) -> String {
+    format!("[[{}] [{}]]", params.join(" "), code.text())
+}
+
+fn word(s: &str) -> String {
+    format!("\"{}", escape(s))
+}
+
+// Numbers are written in full, they read back the same
+fn number(n: f64) -> String {
+    if n.is_nan() {
+        "sqrt -1".to_string()
+    } else if n.is_infinite() {
+        format!("mul {} {}", f64::MAX, if n > 0.0 { 2 } else { -2 })
+    } else {
+        n.to_string()
+    }
+}
+
+// Whether the list literal has the same words, one starting with // would
+// be read as a comment
+fn plain(list: &VecDeque<ValType>) -> bool {
+    list.iter().all(|val| match val {
+        ValType::Str(s) => {
+            !s.is_empty()
+                && !s.starts_with("//")
+                && !s.contains(|c: char| c.is_whitespace() || c == '[' || c == ']')
+        }
+        ValType::List(list, ListType::Ordinary) => plain(list),
+        _ => false,
+    })
+}
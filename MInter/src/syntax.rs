@@ -10,26 +10,183 @@
 pub use Expr::*;
 pub use ValType::*;
 
-use crate::{hashmap, vecdeque};
+use crate::cmdin::Pos;
+use crate::error::{ErrorKind, MuaError};
+use crate::parser::{escape, Block};
+use crate::{hashmap, operators, vecdeque};
 use lazy_static::lazy_static;
 use ordered_float::OrderedFloat;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::collections::hash_map::Iter;
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::collections::{HashMap, VecDeque};
+use std::convert::{TryFrom, TryInto};
 use std::fmt;
-use std::hash::Hash;
+use std::hash::{Hash, Hasher};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
-pub struct ClosureEnv {
-    pub name: String,
-    pub val: ValType,
+// Where a closure was made, functions are told apart by it only by identity
+#[derive(Clone)]
+pub struct Context(pub Rc<RefCell<SymTable>>);
+
+impl fmt::Debug for Context {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Context({:p})", Rc::as_ptr(&self.0))
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+impl PartialEq for Context {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for Context {}
+
+impl Hash for Context {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Rc::as_ptr(&self.0).hash(state);
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ListType {
     Ordinary,
-    Function(Vec<ClosureEnv>, Vec<String>, VecDeque<ValType>),
+    // Context, params, body and the body as code
+    Function(Option<Context>, Vec<String>, VecDeque<ValType>, Rc<Block>),
+}
+
+// The code is only the body parsed, where its words come from doesn't matter
+impl PartialEq for ListType {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (ListType::Ordinary, ListType::Ordinary) => true,
+            (ListType::Function(c1, p1, b1, _), ListType::Function(c2, p2, b2, _)) => {
+                c1 == c2 && p1 == p2 && b1 == b2
+            }
+            _ => false,
+        }
+    }
+}
+
+impl Eq for ListType {}
+
+impl Hash for ListType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        if let ListType::Function(context, params, body, _) = self {
+            context.hash(state);
+            params.hash(state);
+            body.hash(state);
+        }
+    }
+}
+
+// The items of a list. It's used as the VecDeque it holds, with the size
+// limits.size counts kept along once measured, so lists built a step at
+// a time aren't measured all over at each
+#[derive(Default)]
+pub struct Items {
+    items: VecDeque<ValType>,
+    // usize::MAX until measured, and again once changed
+    size: Cell<usize>,
+}
+
+impl Items {
+    pub fn size(&self) -> Option<usize> {
+        Some(self.size.get()).filter(|&size| size != usize::MAX)
+    }
+
+    pub fn set_size(&self, size: usize) {
+        self.size.set(size)
+    }
+
+    pub fn into_inner(self) -> VecDeque<ValType> {
+        self.items
+    }
+}
+
+impl From<VecDeque<ValType>> for Items {
+    fn from(items: VecDeque<ValType>) -> Self {
+        Items {
+            items,
+            size: Cell::new(usize::MAX),
+        }
+    }
+}
+
+impl From<Vec<ValType>> for Items {
+    fn from(items: Vec<ValType>) -> Self {
+        VecDeque::from(items).into()
+    }
+}
+
+impl std::iter::FromIterator<ValType> for Items {
+    fn from_iter<I: IntoIterator<Item = ValType>>(iter: I) -> Self {
+        iter.into_iter().collect::<VecDeque<_>>().into()
+    }
+}
+
+impl IntoIterator for Items {
+    type Item = ValType;
+    type IntoIter = std::collections::vec_deque::IntoIter<ValType>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Items {
+    type Item = &'a ValType;
+    type IntoIter = std::collections::vec_deque::Iter<'a, ValType>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.items.iter()
+    }
+}
+
+impl std::ops::Deref for Items {
+    type Target = VecDeque<ValType>;
+
+    fn deref(&self) -> &VecDeque<ValType> {
+        &self.items
+    }
+}
+
+impl std::ops::DerefMut for Items {
+    fn deref_mut(&mut self) -> &mut VecDeque<ValType> {
+        self.size.set(usize::MAX);
+        &mut self.items
+    }
+}
+
+impl Clone for Items {
+    fn clone(&self) -> Self {
+        Items {
+            items: self.items.clone(),
+            size: self.size.clone(),
+        }
+    }
+}
+
+impl fmt::Debug for Items {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.items.fmt(f)
+    }
+}
+
+impl PartialEq for Items {
+    fn eq(&self, other: &Self) -> bool {
+        self.items == other.items
+    }
+}
+
+impl Eq for Items {}
+
+impl Hash for Items {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.items.hash(state)
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Hash)]
@@ -37,63 +194,79 @@ pub enum ValType {
     Num(OrderedFloat<f64>),
     Str(String),
     Boolean(bool),
-    List(VecDeque<ValType>, ListType),
+    List(Items, ListType),
 
-    // When error occurs
+    // Operation without a meaningful value
     Null,
     // Return value is special dealt with
     Retv(Box<ValType>),
+    // So is a returned call, the caller makes it in place of itself
+    Tail(Box<TailCall>),
+    // And break or continue, lists are left up to their loop
+    Leave(Flow),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Flow {
+    Break,
+    Continue,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct TailCall {
+    pub name: String,
+    pub func: Closure,
+    pub args: Vec<ValType>,
+    pub pos: Option<Pos>,
+}
+
+// What a call needs of a function value, taken without copying its lists
+#[derive(Debug, PartialEq, Eq, Clone, Hash)]
+pub struct Closure {
+    pub context: Option<Context>,
+    pub code: Rc<Block>,
 }
 
 impl ValType {
     pub fn is_num(&self) -> bool {
-        if let Num(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Num(_))
     }
 
     pub fn is_string(&self) -> bool {
-        if let Str(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Str(_))
     }
 
     pub fn is_bool(&self) -> bool {
-        if let Boolean(_) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, Boolean(_))
     }
 
     pub fn is_list(&self) -> bool {
-        if let List(_, _) = self {
-            true
-        } else {
-            false
-        }
+        matches!(self, List(_, _))
     }
 
     pub fn list_is_func(&self) -> Option<i32> {
-        if let List(_, ListType::Function(_, params, _)) = self {
+        if let List(_, ListType::Function(_, params, _, _)) = self {
             Some(params.len() as i32)
         } else {
             None
         }
     }
 
-    pub fn is_ret_value(&self) -> bool {
-        if let Retv(_) = self {
-            true
+    pub fn closure(&self) -> Option<Closure> {
+        if let List(_, ListType::Function(context, _, _, code)) = self {
+            Some(Closure {
+                context: context.clone(),
+                code: Rc::clone(code),
+            })
         } else {
-            false
+            None
         }
     }
 
+    pub fn is_ret_value(&self) -> bool {
+        matches!(self, Retv(_) | Tail(_) | Leave(_))
+    }
+
     pub fn get_ret_value(self) -> ValType {
         if let Retv(box v) = self {
             v
@@ -102,36 +275,18 @@ impl ValType {
         }
     }
 
-    pub fn find_val_in_list(&self, set: &mut HashSet<String>) {
-        if let List(l, _) = self {
-            l.iter().map(|v| v.find_val_in_list(set)).count();
-        } else if let Str(s) = self {
-            let word = if s.starts_with(":") {
-                s[1..].to_string()
-            } else {
-                s.to_string()
-            };
-
-            if KEYWORD.get(word.as_str()).is_none() {
-                set.insert(word);
-            }
-        } else {
-            panic!("find_val_in_list error, elements invalid");
-        }
-    }
-
-    pub fn to_list(self) -> VecDeque<ValType> {
+    pub fn into_list(self) -> Items {
         if let List(v, _) = self {
             v
         } else {
-            vecdeque![self]
+            vecdeque![self].into()
         }
     }
 
     pub fn to_origin(&self) -> String {
         if let Str(s) = self {
             format!("\"{}", s).to_string()
-        } else if let List(l, _) = self{
+        } else if let List(l, _) = self {
             vec2str(l)
         } else {
             self.to_string()
@@ -151,20 +306,41 @@ pub fn vec2str(list: &VecDeque<ValType>) -> String {
     if !list.is_empty() {
         slist.pop(); // Remove Space
     }
-    slist.push_str("]");
+    slist.push(']');
     slist
 }
 
-impl Into<OrderedFloat<f64>> for ValType {
-    fn into(self) -> OrderedFloat<f64> {
-        match self {
-            Num(n) => n,
-            Str(s) => s.parse().unwrap(),
-            Boolean(b) => (b as i64 as f64).into(),
-            List(_, _) => unimplemented!(), // Not supported
-
-            Null => unimplemented!(),
-            Retv(box val) => val.into(),
+impl TryFrom<ValType> for OrderedFloat<f64> {
+    type Error = MuaError;
+
+    fn try_from(val: ValType) -> Result<OrderedFloat<f64>, MuaError> {
+        match val {
+            Num(n) => Ok(n),
+            Str(s) => s.parse().map_err(|_| {
+                MuaError::new(
+                    ErrorKind::TypeMismatch,
+                    format!("expect a number, found word \"{}\"", s),
+                )
+            }),
+            Boolean(b) => Ok((b as i64 as f64).into()),
+            List(_, _) => Err(MuaError::new(
+                ErrorKind::TypeMismatch,
+                "expect a number, found list",
+            )),
+
+            Null => Err(MuaError::new(
+                ErrorKind::TypeMismatch,
+                "expect a number, found null",
+            )),
+            Retv(box val) => val.try_into(),
+            Tail(call) => Err(MuaError::new(
+                ErrorKind::TypeMismatch,
+                format!("expect a number, found call to {}", call.name),
+            )),
+            Leave(flow) => Err(MuaError::new(
+                ErrorKind::TypeMismatch,
+                format!("expect a number, found {}", Leave(flow)),
+            )),
         }
     }
 }
@@ -180,8 +356,11 @@ impl fmt::Display for ValType {
                 write!(f, "{}", &content[1..content.len() - 1])
             }
 
-            Null => write!(f, "{}", "null"),
+            Null => write!(f, "null"),
             Retv(box v) => v.fmt(f),
+            Tail(call) => write!(f, "{}", call.name),
+            Leave(Flow::Break) => write!(f, "break"),
+            Leave(Flow::Continue) => write!(f, "continue"),
         }
     }
 }
@@ -194,28 +373,45 @@ pub enum Expr {
 
     // Operation
     Read,
+    ReadList,
     Erall,
-    
+
     Erase(Box<Expr>),
     Print(Box<Expr>),
     Thing(Box<Expr>),
     Run(Box<Expr>),
     Save(Box<Expr>),
     Load(Box<Expr>),
-    Judge(String, Box<Expr>),
-    Index(String, Box<Expr>),
+    SaveImage(Box<Expr>),
+    LoadImage(Box<Expr>),
+    Judge(JudgeOp, Box<Expr>),
+    Math(MathOp, Box<Expr>),
+    Index(IndexOp, Box<Expr>),
     Make(Box<Expr>, Box<Expr>),
-    Comp(String, Box<Expr>, Box<Expr>),
-    Calc(String, Box<Expr>, Box<Expr>),
-    Extend(String, Box<Expr>, Box<Expr>),
-    Logic(String, Box<Expr>, Box<Expr>),
+    Comp(CompOp, Box<Expr>, Box<Expr>),
+    Calc(CalcOp, Box<Expr>, Box<Expr>),
+    Extend(ExtendOp, Box<Expr>, Box<Expr>),
+    Logic(LogicOp, Box<Expr>, Box<Expr>),
     If(Box<Expr>, Box<Expr>, Box<Expr>),
 
+    // Loops, bodies are kept as code like the lists of if
+    Repeat(Box<Expr>, Box<Expr>),
+    While(Box<Expr>, Box<Expr>),
+    For(Box<Expr>, Box<Expr>),
+    Foreach(Box<Expr>, Box<Expr>),
+    Break,
+    Continue,
+
     // For function
     Return(Box<Expr>),
     Function(String, Vec<Expr>),
     Export(Box<Expr>),
 
+    // Source position of the inner expression
+    At(Option<Pos>, Box<Expr>),
+    // List literal to be run
+    Code(Rc<Block>),
+
     // Empty
     Nop,
 
@@ -223,41 +419,231 @@ pub enum Expr {
     Exit,
 }
 
+impl Expr {
+    // The word written, for operations taking a name as it is
+    pub fn word(&self) -> Option<&str> {
+        if let Value(Str(word)) = self {
+            Some(word)
+        } else {
+            None
+        }
+    }
+}
+
+operators!(JudgeOp {
+    Name = "isname", Number = "isnumber", Word = "isword",
+    List = "islist", Bool = "isbool", Empty = "isempty"
+});
+operators!(IndexOp {
+    First = "first", Last = "last", ButFirst = "butfirst", ButLast = "butlast"
+});
+operators!(MathOp { Random = "random", Int = "int", Sqrt = "sqrt" });
+operators!(CompOp { Eq = "eq", Gt = "gt", Lt = "lt" });
+operators!(CalcOp { Add = "add", Sub = "sub", Mul = "mul", Div = "div", Mod = "mod" });
+operators!(ExtendOp {
+    Word = "word", Sentence = "sentence", List = "list", Join = "join"
+});
+operators!(LogicOp { And = "and", Or = "or", Not = "not" });
+
+// Each call in parentheses with its arguments, as it's grouped:
+// (print (add 1 (mul 2 3)))
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let call = |f: &mut fmt::Formatter<'_>, name: &str, args: &[&Expr]| {
+            write!(f, "({}", name)?;
+            for arg in args {
+                write!(f, " {}", arg)?;
+            }
+            write!(f, ")")
+        };
+        match self {
+            Value(Str(s)) => write!(f, "\"{}", escape(s)),
+            Value(List(list, ListType::Ordinary)) => write!(f, "{}", vec2str(list)),
+            Value(List(_, ListType::Function(_, params, _, code))) => {
+                write!(f, "[[{}] [{}]]", params.join(" "), code.text())
+            }
+            Value(val) => write!(f, "{}", val),
+            Var(name) => write!(f, ":{}", name),
+
+            Read => call(f, "read", &[]),
+            ReadList => call(f, "readlist", &[]),
+            Erall => call(f, "erall", &[]),
+            Nop => call(f, "nop", &[]),
+            Exit => call(f, "exit", &[]),
+            Break => call(f, "break", &[]),
+            Continue => call(f, "continue", &[]),
+
+            Erase(e) => call(f, "erase", &[e]),
+            Print(e) => call(f, "print", &[e]),
+            Thing(e) => call(f, "thing", &[e]),
+            Run(e) => call(f, "run", &[e]),
+            Save(e) => call(f, "save", &[e]),
+            Load(e) => call(f, "load", &[e]),
+            SaveImage(e) => call(f, "saveimage", &[e]),
+            LoadImage(e) => call(f, "loadimage", &[e]),
+            Return(e) => call(f, "return", &[e]),
+            Export(e) => call(f, "export", &[e]),
+            Judge(op, e) => call(f, op.name(), &[e]),
+            Math(op, e) => call(f, op.name(), &[e]),
+            Index(op, e) => call(f, op.name(), &[e]),
+            // Its second operand is only there to fill the slot
+            Logic(LogicOp::Not, e, _) => call(f, "not", &[e]),
+            Make(a, b) => call(f, "make", &[a, b]),
+            Comp(op, a, b) => call(f, op.name(), &[a, b]),
+            Calc(op, a, b) => call(f, op.name(), &[a, b]),
+            Extend(op, a, b) => call(f, op.name(), &[a, b]),
+            Logic(op, a, b) => call(f, op.name(), &[a, b]),
+            If(c, a, b) => call(f, "if", &[c, a, b]),
+            Repeat(a, b) => call(f, "repeat", &[a, b]),
+            While(a, b) => call(f, "while", &[a, b]),
+            For(a, b) => call(f, "for", &[a, b]),
+            Foreach(a, b) => call(f, "foreach", &[a, b]),
+            Function(name, args) => call(f, name, &args.iter().collect::<Vec<_>>()),
+
+            At(_, e) => e.fmt(f),
+            Code(code) => write!(f, "[{}]", code.text()),
+        }
+    }
+}
+
 lazy_static! {
     pub static ref KEYWORD: HashMap<&'static str, i32> = hashmap!(
-        "nop" => 0, "read" => 0, "exit" => 0, "erall" => 0,
+        "nop" => 0, "read" => 0, "readlist" => 0, "exit" => 0, "erall" => 0,
+        "break" => 0, "continue" => 0,
         "print" => 1, "thing" => 1, "erase" => 1, "run" => 1, "export" => 1,
         "isname" => 1, "isnumber" => 1, "isword" => 1, "islist" => 1, "isbool" => 1, "isempty" => 1,
         "not" => 1, "and" => 2, "or" => 2,
         "return" => 1,
         "first" => 1, "last" => 1, "butfirst" => 1, "butlast" => 1,
-        "save" => 1, "load" => 1,
+        "random" => 1, "int" => 1, "sqrt" => 1,
+        "save" => 1, "load" => 1, "saveimage" => 1, "loadimage" => 1,
         "eq" => 2, "gt" => 2, "lt" => 2,
         "add" => 2, "sub" => 2, "mul" => 2, "div" => 2, "mod" => 2,
         "make" => 2,
-        "sentence" => 2, "list" => 2, "join" => 2,
+        "repeat" => 2, "while" => 2, "for" => 2, "foreach" => 2,
+        "word" => 2, "sentence" => 2, "list" => 2, "join" => 2,
         "if" => 3
     );
 }
 
+pub type HostFn = dyn Fn(&[ValType]) -> Result<ValType, MuaError>;
+
+// A primitive provided by the program MUA is embedded in
+pub struct Host {
+    pub arity: usize,
+    pub func: Box<HostFn>,
+}
+
+impl fmt::Debug for Host {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Host")
+            .field("arity", &self.arity)
+            .finish_non_exhaustive()
+    }
+}
+
+// The names a table has a slot for, in the order they got one. Calls of
+// a function share the names of its body, found before it runs, so the
+// vm can take them by slot. A name made apart from those gives the
+// table its own copy, with another id
 #[derive(Debug)]
+pub struct Names {
+    id: u64,
+    names: Vec<String>,
+    slots: HashMap<String, usize>,
+}
+
+static NAMES_MADE: AtomicU64 = AtomicU64::new(0);
+
+impl Names {
+    pub fn new() -> Self {
+        Names {
+            id: NAMES_MADE.fetch_add(1, Ordering::Relaxed) + 1,
+            names: vec![],
+            slots: HashMap::new(),
+        }
+    }
+
+    // Code compiled against these names may take slots by index as long
+    // as the id is the same, names are only ever added
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub fn len(&self) -> usize {
+        self.names.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.names.is_empty()
+    }
+
+    pub fn slot(&self, name: &str) -> Option<usize> {
+        self.slots.get(name).copied()
+    }
+
+    pub fn name(&self, slot: usize) -> &str {
+        &self.names[slot]
+    }
+
+    pub fn add(&mut self, name: &str) -> usize {
+        if let Some(slot) = self.slot(name) {
+            return slot;
+        }
+        self.names.push(name.to_string());
+        self.slots.insert(name.to_string(), self.names.len() - 1);
+        self.names.len() - 1
+    }
+}
+
+impl Default for Names {
+    fn default() -> Self {
+        Names::new()
+    }
+}
+
+impl Clone for Names {
+    fn clone(&self) -> Self {
+        Names {
+            names: self.names.clone(),
+            slots: self.slots.clone(),
+            ..Names::new()
+        }
+    }
+}
+
 pub struct SymTable {
-    local: HashMap<String, ValType>,
+    names: Rc<Names>,
+    // Values by the slots of names, None where one is not made
+    slots: Vec<Option<ValType>>,
     global: Option<Rc<RefCell<SymTable>>>,
     context: Option<Rc<RefCell<SymTable>>>,
     func: HashMap<String, i32>,
+    // Only the global table has them, erall leaves them
+    hosts: HashMap<String, Rc<Host>>,
 }
 
 impl SymTable {
     pub fn new(
         global: Option<Rc<RefCell<SymTable>>>,
         context: Option<Rc<RefCell<SymTable>>>,
+    ) -> Self {
+        SymTable::with_names(global, context, Rc::new(Names::new()))
+    }
+
+    // A table with slots for names, none of them made yet
+    pub fn with_names(
+        global: Option<Rc<RefCell<SymTable>>>,
+        context: Option<Rc<RefCell<SymTable>>>,
+        names: Rc<Names>,
     ) -> Self {
         SymTable {
-            local: HashMap::new(),
-            global: global,
-            context: context,
+            slots: vec![None; names.len()],
+            names,
+            global,
+            context,
             func: HashMap::new(),
+            hosts: HashMap::new(),
         }
     }
 
@@ -266,64 +652,201 @@ impl SymTable {
     }
 
     pub fn get_global(&self) -> Rc<RefCell<SymTable>> {
-        Rc::clone(self.global.as_ref().unwrap())
+        Rc::clone(self.global())
+    }
+
+    pub fn global(&self) -> &Rc<RefCell<SymTable>> {
+        self.global.as_ref().unwrap()
+    }
+
+    // The table of the function this one's function is made in
+    pub fn get_context(&self) -> Option<Rc<RefCell<SymTable>>> {
+        self.context.clone()
+    }
+
+    pub fn set_context(&mut self, context: Option<Rc<RefCell<SymTable>>>) {
+        self.context = context
+    }
+
+    pub fn names(&self) -> &Rc<Names> {
+        &self.names
+    }
+
+    // The slot name has here, given one if it has none
+    pub fn slot_of(&mut self, name: &str) -> usize {
+        match self.names.slot(name) {
+            Some(slot) => slot,
+            None => {
+                let slot = Rc::make_mut(&mut self.names).add(name);
+                self.slots.resize(self.names.len(), None);
+                slot
+            }
+        }
     }
 
-    pub fn exist_local(&self, x: &String) -> bool {
-        self.local.get(x).is_some()
+    pub fn slot(&self, slot: usize) -> Option<&ValType> {
+        self.slots.get(slot)?.as_ref()
     }
 
-    pub fn exist_context(&self, x: &String) -> bool {
-        self.context.as_ref().unwrap().borrow().exist_local(x)
+    // Make the name of slot, as bind does. A name made something else is
+    // no function any more
+    pub fn set(&mut self, slot: usize, val: ValType) -> Option<ValType> {
+        if let Some(param_num) = val.list_is_func() {
+            let name = self.names.name(slot).to_string();
+            self.add_func(&name, param_num);
+        } else if !self.func.is_empty() {
+            let name = self.names.name(slot);
+            self.func.remove(name);
+        }
+        self.slots[slot].replace(val)
     }
 
-    pub fn exist_global(&self, x: &String) -> bool {
+    fn get(&self, x: &str) -> Option<&ValType> {
+        self.slot(self.names.slot(x)?)
+    }
+
+    pub fn exist_local(&self, x: &str) -> bool {
+        self.get(x).is_some()
+    }
+
+    pub fn exist_context(&self, x: &str) -> bool {
+        self.context.as_ref().is_some_and(|context| {
+            let context = context.borrow();
+            context.exist_local(x) || context.exist_context(x)
+        })
+    }
+
+    pub fn exist_global(&self, x: &str) -> bool {
         self.global.as_ref().unwrap().borrow().exist_local(x)
     }
 
-    pub fn lookup_local(&self, x: &String) -> Option<ValType> {
-        self.local.get(x).map(|v| v.to_owned())
+    pub fn lookup_local(&self, x: &str) -> Option<ValType> {
+        self.get(x).cloned()
     }
 
-    pub fn lookup_context(&self, x: &String) -> Option<ValType> {
-        self.context.as_ref().unwrap().borrow().lookup_local(x)
+    // The functions this one is made in, innermost first
+    pub fn lookup_context(&self, x: &str) -> Option<ValType> {
+        let context = self.context.as_ref()?.borrow();
+        context
+            .lookup_local(x)
+            .or_else(|| context.lookup_context(x))
     }
 
-    pub fn lookup_global(&self, x: &String) -> Option<ValType> {
+    pub fn lookup_global(&self, x: &str) -> Option<ValType> {
         self.global.as_ref().unwrap().borrow().lookup_local(x)
     }
 
-    pub fn lookup(&self, x: &String) -> Option<ValType> {
-        self.lookup_local(x).or(
-            self.lookup_global(x)
-        )
+    pub fn lookup(&self, x: &str) -> Option<ValType> {
+        self.lookup_local(x)
+            .or_else(|| self.lookup_context(x))
+            .or_else(|| self.lookup_global(x))
+    }
+
+    // What f takes of the value lookup finds, the value is not copied
+    pub fn lookup_with<T>(&self, x: &str, mut f: impl FnMut(&ValType) -> T) -> Option<T> {
+        if let Some(val) = self.get(x) {
+            return Some(f(val));
+        }
+        if let Some(res) = self.context_with(x, &mut f) {
+            return Some(res);
+        }
+        self.global().borrow().get(x).map(f)
+    }
+
+    // Same, in the tables of the functions this one is made in
+    pub fn context_with<T>(&self, x: &str, mut f: impl FnMut(&ValType) -> T) -> Option<T> {
+        let mut context = self.context.clone();
+        while let Some(table) = context {
+            let table = table.borrow();
+            if let Some(val) = table.get(x) {
+                return Some(f(val));
+            }
+            context = table.context.clone();
+        }
+        None
+    }
+
+    // What a function made in env sees of it. It's a copy, as closures
+    // capture values. At the top level names are looked up as they are
+    pub fn capture(env: &Rc<RefCell<SymTable>>) -> Option<Context> {
+        let table = env.borrow();
+        if Rc::ptr_eq(env, table.global.as_ref()?) {
+            return None;
+        }
+        Some(Context(Rc::new(RefCell::new(SymTable {
+            names: Rc::clone(&table.names),
+            slots: table.slots.clone(),
+            global: table.global.clone(),
+            context: table.context.clone(),
+            func: table.func.clone(),
+            hosts: HashMap::new(),
+        }))))
     }
 
     pub fn bind(&mut self, var: String, val: ValType) -> Option<ValType> {
-        val.list_is_func()
-            .map(|param_num| self.add_func(&var, param_num));
-        self.local.insert(var, val)
+        let slot = self.slot_of(&var);
+        self.set(slot, val)
     }
 
+    // Returns the exported value, None if var is not local
     pub fn export(&mut self, var: String) -> Option<ValType> {
-        let val = self.lookup_local(&var).unwrap();
-        self.global.as_ref().unwrap().borrow_mut().bind(var, val)
+        let val = self.lookup_local(&var)?;
+        self.global
+            .as_ref()
+            .unwrap()
+            .borrow_mut()
+            .bind(var, val.clone());
+        Some(val)
     }
 
     pub fn unbind(&mut self, var: String) -> Option<ValType> {
         self.remove_func(&var);
-        self.local.remove(&var)
+        let slot = self.names.slot(&var)?;
+        self.slots[slot].take()
     }
 
-    pub fn get_keys_values(&self) -> Iter<'_, String, ValType> {
-        self.local.iter()
+    pub fn get_keys_values(&self) -> impl Iterator<Item = (&String, &ValType)> {
+        self.names
+            .names
+            .iter()
+            .zip(&self.slots)
+            .filter_map(|(name, val)| Some((name, val.as_ref()?)))
     }
 
+    // The names bound to functions, with how many arguments they take
+    pub fn functions(&self) -> Iter<'_, String, i32> {
+        self.func.iter()
+    }
+
+    // Names provided by the system, they can be erased like any others
+    #[allow(clippy::approx_constant)]
+    pub fn predefine(&mut self) {
+        self.bind("pi".to_string(), Num(3.14159.into()));
+    }
+
+    // Names keep their slots, code compiled for the table stays right
     pub fn clear_all(&mut self) {
-        self.local.clear();
+        self.slots.iter_mut().for_each(|val| *val = None);
         self.func.clear();
     }
 
+    pub fn register(&mut self, name: String, host: Host) {
+        self.hosts.insert(name, Rc::new(host));
+    }
+
+    // Names of the primitives registered, only the global table has any
+    pub fn host_names(&self) -> impl Iterator<Item = &String> {
+        self.hosts.keys()
+    }
+
+    pub fn host(&self, name: &str) -> Option<Rc<Host>> {
+        match &self.global {
+            Some(global) if !std::ptr::eq(global.as_ptr(), self) => global.borrow().host(name),
+            _ if self.hosts.is_empty() => None,
+            _ => self.hosts.get(name).cloned(),
+        }
+    }
+
     fn add_func(&mut self, func_name: &str, param_num: i32) -> Option<i32> {
         self.func.insert(func_name.to_string(), param_num)
     }
@@ -333,15 +856,25 @@ impl SymTable {
     }
 
     fn is_func_local(&self, func_name: &str) -> Option<i32> {
-        self.func.get(func_name).map(|&i| i)
+        self.func.get(func_name).copied()
+    }
+
+    fn is_func_context(&self, func_name: &str) -> Option<i32> {
+        let context = self.context.as_ref()?.borrow();
+        context
+            .is_func_local(func_name)
+            .or_else(|| context.is_func_context(func_name))
     }
 
     pub fn is_func(&self, func_name: &str) -> Option<i32> {
-        self.is_func_local(func_name).or(self
-            .global
-            .as_ref()
-            .unwrap()
-            .borrow()
-            .is_func_local(func_name))
+        self.is_func_local(func_name)
+            .or_else(|| self.is_func_context(func_name))
+            .or_else(|| {
+                self.global
+                    .as_ref()
+                    .unwrap()
+                    .borrow()
+                    .is_func_local(func_name)
+            })
     }
 }
@@ -0,0 +1,1163 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-09 16:40:05
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-09 16:40:05
+ * @Description: file information
+ * @Copyright: Copyright (c) 2022
+ */
+
+// Expressions compiled to bytecode, run on a stack machine. MUA calls
+// push frames here instead of recursing, only load nests a new machine.
+// Names are resolved as code is compiled, to slots of the table it runs
+// in and of the global table
+
+use crate::cmdin::Pos;
+use crate::error::MuaError;
+use crate::interp::{Frame, Runtime};
+use crate::parser::{Block, Stmt};
+use crate::prim::{self, num, Callee};
+use crate::syntax::*;
+use crate::Input;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+// Operands index the pools of the chunk
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    Const(u32),
+    // Function literal, capturing locals
+    Closure(u32),
+    Load(u32),
+    // Check the top is a word, as the name of make, thing or export
+    Name(&'static str),
+    // Make of a name written as a word
+    MakeVar(u32),
+    Make,
+    Thing,
+    // Erase of the name written, None if it's not a word
+    Erase(Option<u32>),
+    Print,
+    Judge(JudgeOp),
+    Index(IndexOp),
+    Math(MathOp),
+    // Turn the top into a number
+    Num,
+    // Only the second operand is turned into a number, the first is one
+    Calc(CalcOp),
+    Comp(CompOp),
+    Logic(LogicOp),
+    Extend(ExtendOp),
+    Read,
+    ReadList,
+    Return,
+    Export,
+    Save,
+    LoadFile,
+    SaveImage,
+    LoadImage,
+    Erall,
+    Exit,
+
+    Jump(u32),
+    JumpUnless(u32),
+    // Run a list literal, in tail position or not
+    Block(u32, bool),
+    // Run the list on the top
+    RunList,
+    // Loops, each list not written as a literal is on the stack
+    Repeat(Option<u32>),
+    While(Option<u32>, Option<u32>),
+    For(Option<u32>),
+    Foreach,
+    // Find the function, its arguments come next
+    Func(u32),
+    Call(u32),
+}
+
+// A name as the code was compiled: its slot in the table the code runs
+// in if it has one there, and in the global table
+#[derive(Debug)]
+struct Var {
+    name: String,
+    local: Option<usize>,
+    global: usize,
+}
+
+#[derive(Debug)]
+struct Site {
+    var: u32,
+    argc: usize,
+    pos: Option<Pos>,
+    tail: bool,
+}
+
+// Names of the tables a chunk is compiled for, by id
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Scope {
+    local: u64,
+    global: u64,
+}
+
+impl Scope {
+    fn of(env: &Rc<RefCell<SymTable>>) -> Self {
+        let table = env.borrow();
+        let global = table.global().borrow().names().id();
+        Scope {
+            local: table.names().id(),
+            global,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Chunk {
+    code: Vec<Op>,
+    consts: Vec<ValType>,
+    vars: Vec<Var>,
+    blocks: Vec<Rc<Block>>,
+    sites: Vec<Site>,
+    // Source of each range of code, inner ranges come first
+    spans: Vec<(usize, usize, Pos)>,
+    tail: bool,
+    scope: Scope,
+}
+
+impl Chunk {
+    fn pos_at(&self, ip: usize) -> Option<&Pos> {
+        self.spans
+            .iter()
+            .find(|(start, end, _)| *start <= ip && ip < *end)
+            .map(|(_, _, pos)| pos)
+    }
+}
+
+struct Compiler {
+    chunk: Chunk,
+    global: Rc<RefCell<SymTable>>,
+    // Of the table the code runs in, None at the top level
+    names: Option<Rc<Names>>,
+}
+
+pub fn compile(expr: &Expr, tail: bool, env: &Rc<RefCell<SymTable>>) -> Chunk {
+    let global = env.borrow().get_global();
+    let names = if Rc::ptr_eq(env, &global) {
+        None
+    } else {
+        Some(Rc::clone(env.borrow().names()))
+    };
+    let mut c = Compiler {
+        chunk: Chunk {
+            code: vec![],
+            consts: vec![],
+            vars: vec![],
+            blocks: vec![],
+            sites: vec![],
+            spans: vec![],
+            tail,
+            scope: Scope::of(env),
+        },
+        global,
+        names,
+    };
+    if tail {
+        c.tail(expr);
+    } else {
+        c.expr(expr);
+    }
+    // Names may have got slots in the global table meanwhile
+    c.chunk.scope = Scope::of(env);
+    c.chunk
+}
+
+impl Compiler {
+    fn emit(&mut self, op: Op) -> usize {
+        self.chunk.code.push(op);
+        self.chunk.code.len() - 1
+    }
+
+    fn constant(&mut self, val: &ValType) -> u32 {
+        self.chunk.consts.push(val.clone());
+        (self.chunk.consts.len() - 1) as u32
+    }
+
+    fn var(&mut self, name: &str) -> u32 {
+        if let Some(i) = self.chunk.vars.iter().position(|var| var.name == name) {
+            return i as u32;
+        }
+        let global = self.global.borrow_mut().slot_of(name);
+        let local = match &self.names {
+            Some(names) => names.slot(name),
+            None => Some(global),
+        };
+        self.chunk.vars.push(Var {
+            name: name.to_string(),
+            local,
+            global,
+        });
+        (self.chunk.vars.len() - 1) as u32
+    }
+
+    fn block(&mut self, block: &Rc<Block>) -> u32 {
+        self.chunk.blocks.push(Rc::clone(block));
+        (self.chunk.blocks.len() - 1) as u32
+    }
+
+    fn here(&self) -> usize {
+        self.chunk.code.len()
+    }
+
+    fn patch(&mut self, at: usize) {
+        let to = self.here() as u32;
+        match &mut self.chunk.code[at] {
+            Op::Jump(t) | Op::JumpUnless(t) => *t = to,
+            _ => unreachable!("only jumps are patched"),
+        }
+    }
+
+    fn at(&mut self, pos: &Option<Pos>, f: impl FnOnce(&mut Self)) {
+        let start = self.here();
+        f(self);
+        if let Some(pos) = pos {
+            self.chunk.spans.push((start, self.here(), pos.clone()));
+        }
+    }
+
+    fn expr(&mut self, expr: &Expr) {
+        match expr {
+            Value(val) => {
+                let i = self.constant(val);
+                if val.list_is_func().is_some() {
+                    self.emit(Op::Closure(i));
+                } else {
+                    self.emit(Op::Const(i));
+                }
+            }
+            Var(x) => {
+                let var = self.var(x);
+                self.emit(Op::Load(var));
+            }
+            Make(box Value(Str(x)), e) => {
+                self.expr(e);
+                let var = self.var(x);
+                self.emit(Op::MakeVar(var));
+            }
+            Make(x, e) => {
+                self.expr(x);
+                self.emit(Op::Name("make"));
+                self.expr(e);
+                self.emit(Op::Make);
+            }
+            Erase(n) => {
+                let var = n.word().map(|n| self.var(n));
+                self.emit(Op::Erase(var));
+            }
+            Print(data) => {
+                self.expr(data);
+                self.emit(Op::Print);
+            }
+            Thing(box Value(Str(x))) => {
+                let var = self.var(x);
+                self.emit(Op::Load(var));
+            }
+            Thing(data) => {
+                self.expr(data);
+                self.emit(Op::Name("thing"));
+                self.emit(Op::Thing);
+            }
+            Run(cmd) => self.list(cmd, false),
+            Judge(op, value) => {
+                self.expr(value);
+                self.emit(Op::Judge(*op));
+            }
+            Index(op, value) => {
+                self.expr(value);
+                self.emit(Op::Index(*op));
+            }
+            Math(op, n) => {
+                self.number(n);
+                self.emit(Op::Math(*op));
+            }
+            Calc(op, n1, n2) => {
+                self.number(n1);
+                self.expr(n2);
+                self.emit(Op::Calc(*op));
+            }
+            Comp(op, n1, n2) => {
+                self.expr(n1);
+                self.expr(n2);
+                self.emit(Op::Comp(*op));
+            }
+            Logic(op, n1, n2) => {
+                self.expr(n1);
+                self.expr(n2);
+                self.emit(Op::Logic(*op));
+            }
+            Extend(op, l1, l2) => {
+                self.expr(l1);
+                self.expr(l2);
+                self.emit(Op::Extend(*op));
+            }
+            If(b, r1, r2) => self.branch(b, r1, r2, false),
+            Repeat(n, body) => {
+                self.expr(n);
+                let body = self.loop_list(body);
+                self.emit(Op::Repeat(body));
+            }
+            While(cond, body) => {
+                let cond = self.loop_list(cond);
+                let body = self.loop_list(body);
+                self.emit(Op::While(cond, body));
+            }
+            For(control, body) => {
+                self.expr(control);
+                let body = self.loop_list(body);
+                self.emit(Op::For(body));
+            }
+            Foreach(list, func) => {
+                self.expr(list);
+                self.expr(func);
+                self.emit(Op::Foreach);
+            }
+            Break | Continue => {
+                let flow = if let Break = expr {
+                    Flow::Break
+                } else {
+                    Flow::Continue
+                };
+                let i = self.constant(&Leave(flow));
+                self.emit(Op::Const(i));
+            }
+            Read => {
+                self.emit(Op::Read);
+            }
+            ReadList => {
+                self.emit(Op::ReadList);
+            }
+            Return(expr) => {
+                self.expr(expr);
+                self.emit(Op::Return);
+            }
+            Export(expr) => {
+                self.expr(expr);
+                self.emit(Op::Name("export"));
+                self.emit(Op::Export);
+            }
+            Function(op, exprs) => self.call(op, exprs, None, false),
+            Code(block) => {
+                let block = self.block(block);
+                self.emit(Op::Block(block, false));
+            }
+            Save(filename) => {
+                self.expr(filename);
+                self.emit(Op::Save);
+            }
+            Load(filename) => {
+                self.expr(filename);
+                self.emit(Op::LoadFile);
+            }
+            SaveImage(filename) => {
+                self.expr(filename);
+                self.emit(Op::SaveImage);
+            }
+            LoadImage(filename) => {
+                self.expr(filename);
+                self.emit(Op::LoadImage);
+            }
+            Erall => {
+                self.emit(Op::Erall);
+            }
+            At(pos, expr) => self.at(pos, |c| match &**expr {
+                Function(op, exprs) => c.call(op, exprs, pos.clone(), false),
+                expr => c.expr(expr),
+            }),
+            Nop => {
+                let i = self.constant(&ValType::Null);
+                self.emit(Op::Const(i));
+            }
+            Exit => {
+                self.emit(Op::Exit);
+            }
+        }
+    }
+
+    // An operand taken as a number, turned into one unless it is already
+    fn number(&mut self, expr: &Expr) {
+        self.expr(expr);
+        match expr {
+            Value(Num(_)) | At(_, box Calc(..)) | At(_, box Math(..)) => (),
+            _ => {
+                self.emit(Op::Num);
+            }
+        }
+    }
+
+    // A statement which is the last thing its function does
+    fn tail(&mut self, expr: &Expr) {
+        match expr {
+            At(pos, inner) if !matches!(**inner, Function(..)) => self.at(pos, |c| c.tail(inner)),
+            Return(box At(pos, box Function(op, exprs))) => {
+                self.at(pos, |c| c.call(op, exprs, pos.clone(), true))
+            }
+            If(b, r1, r2) => self.branch(b, r1, r2, true),
+            Run(cmd) => self.list(cmd, true),
+            _ => self.expr(expr),
+        }
+    }
+
+    fn branch(&mut self, b: &Expr, r1: &Expr, r2: &Expr, tail: bool) {
+        self.expr(b);
+        let to_else = self.emit(Op::JumpUnless(0));
+        self.list(r1, tail);
+        let to_end = self.emit(Op::Jump(0));
+        self.patch(to_else);
+        self.list(r2, tail);
+        self.patch(to_end);
+    }
+
+    // Only list literals are run in tail position
+    fn list(&mut self, expr: &Expr, tail: bool) {
+        if let Code(block) = expr {
+            let block = self.block(block);
+            self.emit(Op::Block(block, tail));
+        } else {
+            self.expr(expr);
+            self.emit(Op::RunList);
+        }
+    }
+
+    // A literal is kept for the loop, anything else is computed
+    fn loop_list(&mut self, expr: &Expr) -> Option<u32> {
+        if let Code(block) = expr {
+            Some(self.block(block))
+        } else {
+            self.expr(expr);
+            None
+        }
+    }
+
+    fn call(&mut self, op: &str, exprs: &[Expr], pos: Option<Pos>, tail: bool) {
+        let var = self.var(op);
+        self.chunk.sites.push(Site {
+            var,
+            argc: exprs.len(),
+            pos,
+            tail,
+        });
+        let site = (self.chunk.sites.len() - 1) as u32;
+        self.emit(Op::Func(site));
+        for expr in exprs {
+            self.expr(expr);
+        }
+        self.emit(Op::Call(site));
+    }
+}
+
+// Statements are compiled along with their parse, again only if they run
+// in tables with other names
+fn stmt_code(stmt: &Stmt, tail: bool, env: &Rc<RefCell<SymTable>>) -> Rc<Chunk> {
+    if let Some(chunk) = &*stmt.code.borrow() {
+        if chunk.tail == tail && chunk.scope == Scope::of(env) {
+            return Rc::clone(chunk);
+        }
+    }
+    let chunk = Rc::new(compile(&stmt.expr, tail, env));
+    *stmt.code.borrow_mut() = Some(Rc::clone(&chunk));
+    chunk
+}
+
+enum Activation {
+    Exec {
+        chunk: Rc<Chunk>,
+        ip: usize,
+        env: Rc<RefCell<SymTable>>,
+    },
+    Block(BlockRun),
+    Loop(LoopRun),
+    // Where a function returns to
+    Call {
+        global: Rc<RefCell<SymTable>>,
+    },
+}
+
+// Statements of a list, one at a time
+struct BlockRun {
+    block: Rc<Block>,
+    next: usize,
+    env: Rc<RefCell<SymTable>>,
+    tail: bool,
+    res: ValType,
+    // Code of the statement running, and how far it has got
+    code: Option<Rc<Chunk>>,
+    ip: usize,
+}
+
+// Why code stops running
+enum Stop {
+    End,
+    // A frame is pushed above, the code goes on when it's done
+    Frame,
+}
+
+// A loop, a round at a time
+struct LoopRun {
+    looping: Looping,
+    env: Rc<RefCell<SymTable>>,
+    // What runs above, its value comes next
+    running: Option<Part>,
+}
+
+enum Looping {
+    Repeat {
+        body: Rc<Block>,
+        left: u64,
+    },
+    While {
+        cond: Rc<Block>,
+        body: Rc<Block>,
+    },
+    For {
+        var: String,
+        steps: prim::Steps,
+        body: Rc<Block>,
+    },
+    Foreach {
+        items: VecDeque<ValType>,
+        name: String,
+        callee: Callee,
+    },
+}
+
+#[derive(Clone, Copy)]
+enum Part {
+    Cond,
+    Body,
+}
+
+struct Vm<'r> {
+    rt: &'r mut Runtime,
+    stack: Vec<ValType>,
+    // What each call on the way runs, pushed by Func, taken by Call
+    callees: Vec<Callee>,
+    frames: Vec<Activation>,
+    depth: usize,
+}
+
+pub fn run(rt: &mut Runtime, expr: &Expr, env: Rc<RefCell<SymTable>>) -> Result<ValType, MuaError> {
+    let depth = rt.stack.len();
+    let mut vm = Vm {
+        rt,
+        stack: vec![],
+        callees: vec![],
+        frames: vec![Activation::Exec {
+            chunk: Rc::new(compile(expr, false, &env)),
+            ip: 0,
+            env,
+        }],
+        depth,
+    };
+    vm.finish()
+}
+
+// Call a function value from out of any code
+pub fn call(
+    rt: &mut Runtime,
+    call: TailCall,
+    global: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    prim::check_depth(rt)?;
+    let depth = rt.stack.len();
+    let mut vm = Vm {
+        rt,
+        stack: vec![],
+        callees: vec![],
+        frames: vec![Activation::Call {
+            global: Rc::clone(&global),
+        }],
+        depth,
+    };
+    vm.invoke(call, &global);
+    vm.finish()
+}
+
+pub fn interpretor(
+    rt: &mut Runtime,
+    input: &mut Input,
+    env: Rc<RefCell<SymTable>>,
+) -> Result<ValType, MuaError> {
+    use crate::parser::parse;
+
+    prim::check_stack()?;
+    let mut res = ValType::Null;
+    while let Some(expr) = parse(input, Rc::clone(&env))? {
+        res = run(rt, &expr, Rc::clone(&env))?;
+        if res.is_ret_value() {
+            return prim::outside_loop(res).map(ValType::get_ret_value);
+        }
+    }
+    Ok(res)
+}
+
+// What the name of var is, looked up as the table would. The slot var
+// has in env holds only while env has the names the code is compiled
+// for, one made apart since is looked up by name
+fn find<T>(
+    var: &Var,
+    env: &SymTable,
+    scope: &Scope,
+    mut f: impl FnMut(&ValType) -> T,
+) -> Option<T> {
+    let local = match var.local {
+        Some(slot) => env.slot(slot),
+        None if env.names().id() != scope.local => {
+            env.names().slot(&var.name).and_then(|slot| env.slot(slot))
+        }
+        None => None,
+    };
+    if let Some(val) = local {
+        return Some(f(val));
+    }
+    if let Some(res) = env.context_with(&var.name, &mut f) {
+        return Some(res);
+    }
+    env.global().borrow().slot(var.global).map(f)
+}
+
+impl Vm<'_> {
+    fn finish(&mut self) -> Result<ValType, MuaError> {
+        while !self.frames.is_empty() {
+            if let Err(e) = self.step() {
+                return Err(self.unwind(e));
+            }
+        }
+        Ok(self.stack.pop().unwrap_or(ValType::Null))
+    }
+
+    fn pop(&mut self) -> ValType {
+        self.stack.pop().expect("operand stack underflow")
+    }
+
+    fn push(&mut self, val: ValType) {
+        self.stack.push(val);
+    }
+
+    fn step(&mut self) -> Result<(), MuaError> {
+        match self.frames.last() {
+            Some(Activation::Exec { .. }) => self.exec(),
+            Some(Activation::Block(_)) => self.run_block(),
+            Some(Activation::Loop(_)) => self.next_round(),
+            Some(Activation::Call { .. }) => {
+                let res = self.pop();
+                self.ret(res)
+            }
+            None => Ok(()),
+        }
+    }
+
+    // Run the code on the top until it ends or a frame goes above it
+    fn exec(&mut self) -> Result<(), MuaError> {
+        let at = self.frames.len() - 1;
+        let (chunk, env, mut ip) = match &self.frames[at] {
+            Activation::Exec { chunk, ip, env } => (Rc::clone(chunk), Rc::clone(env), *ip),
+            _ => unreachable!("only code is run"),
+        };
+        match self.ops(&chunk, &env, &mut ip) {
+            Ok(Stop::End) => {
+                self.frames.truncate(at);
+                Ok(())
+            }
+            stop => {
+                if let Activation::Exec { ip: saved, .. } = &mut self.frames[at] {
+                    *saved = ip;
+                }
+                stop.map(drop)
+            }
+        }
+    }
+
+    fn block_run(&mut self, at: usize) -> &mut BlockRun {
+        match &mut self.frames[at] {
+            Activation::Block(run) => run,
+            _ => unreachable!("statements belong to a block"),
+        }
+    }
+
+    // Run the statements of the list on the top, one after another, until
+    // it ends or a frame goes above it
+    fn run_block(&mut self) -> Result<(), MuaError> {
+        let at = self.frames.len() - 1;
+        let run = self.block_run(at);
+        let (block, env, tail) = (Rc::clone(&run.block), Rc::clone(&run.env), run.tail);
+        let (mut code, mut ip) = (run.code.take(), run.ip);
+        loop {
+            if let Some(chunk) = &code {
+                let stop = self.ops(chunk, &env, &mut ip);
+                if !matches!(stop, Ok(Stop::End)) {
+                    let run = self.block_run(at);
+                    run.code = code;
+                    run.ip = ip;
+                    return stop.map(drop);
+                }
+                let res = self.pop();
+                if res.is_ret_value() {
+                    self.frames.truncate(at);
+                    self.leave(tail, res);
+                    return Ok(());
+                }
+                self.block_run(at).res = res;
+            }
+
+            let next = self.block_run(at).next;
+            let stmt = if next < block.len() {
+                block.statement(next, &env)?
+            } else {
+                None
+            };
+            match stmt {
+                Some(stmt) => {
+                    code = Some(stmt_code(&stmt, tail, &env));
+                    ip = 0;
+                    self.block_run(at).next = stmt.end;
+                }
+                None => {
+                    let res = std::mem::replace(&mut self.block_run(at).res, ValType::Null);
+                    self.frames.truncate(at);
+                    self.leave(tail, res);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn ops(
+        &mut self,
+        chunk: &Chunk,
+        env: &Rc<RefCell<SymTable>>,
+        ip: &mut usize,
+    ) -> Result<Stop, MuaError> {
+        loop {
+            let op = match chunk.code.get(*ip) {
+                Some(op) => *op,
+                None => return Ok(Stop::End),
+            };
+            *ip += 1;
+            prim::tick(self.rt)?;
+            let res = match op {
+                Op::Const(i) => chunk.consts[i as usize].clone(),
+                Op::Closure(i) => prim::value(&chunk.consts[i as usize], env),
+                Op::Load(var) => {
+                    let var = &chunk.vars[var as usize];
+                    match find(var, &env.borrow(), &chunk.scope, ValType::clone) {
+                        Some(val) => val,
+                        None => prim::thing(&var.name, env)?,
+                    }
+                }
+                Op::Name(op) => {
+                    let val = self.pop();
+                    ValType::Str(prim::name(val, op)?)
+                }
+                Op::MakeVar(var) => {
+                    let val = self.pop();
+                    let var = &chunk.vars[var as usize];
+                    let mut table = env.borrow_mut();
+                    match var.local {
+                        Some(slot) => table.set(slot, val.clone()),
+                        None => table.bind(var.name.clone(), val.clone()),
+                    };
+                    val
+                }
+                Op::Make => {
+                    let val = self.pop();
+                    let name = self.pop().to_string();
+                    prim::make(name, val, env)
+                }
+                Op::Thing => {
+                    let name = self.pop().to_string();
+                    prim::thing(&name, env)?
+                }
+                Op::Erase(var) => {
+                    let name = var.map(|var| chunk.vars[var as usize].name.as_str());
+                    prim::erase(name, env)?
+                }
+                Op::Print => {
+                    let val = self.pop();
+                    prim::print(self.rt, val)?
+                }
+                Op::Judge(op) => {
+                    let val = self.pop();
+                    prim::judge(op, val, env)
+                }
+                Op::Index(op) => {
+                    let val = self.pop();
+                    prim::index(op, val)?
+                }
+                Op::Math(op) => {
+                    let v = num(self.pop())?;
+                    prim::math(self.rt, op, v)
+                }
+                Op::Num => ValType::Num(num(self.pop())?),
+                Op::Calc(op) => {
+                    let v2 = num(self.pop())?;
+                    let v1 = num(self.pop())?;
+                    prim::calc(op, v1, v2)?
+                }
+                Op::Comp(op) => {
+                    let v2 = self.pop();
+                    let v1 = self.pop();
+                    prim::comp(op, v1, v2)?
+                }
+                Op::Logic(op) => {
+                    let v2 = self.pop();
+                    let v1 = self.pop();
+                    prim::logic(op, v1, v2)?
+                }
+                Op::Extend(op) => {
+                    let v2 = self.pop();
+                    let v1 = self.pop();
+                    prim::extend(self.rt, op, v1, v2)?
+                }
+                Op::Read => prim::read(self.rt)?,
+                Op::ReadList => prim::read_list(self.rt)?,
+                Op::Return => Retv(Box::new(self.pop())),
+                Op::Export => {
+                    let name = self.pop().to_string();
+                    prim::export(name, env)?
+                }
+                Op::Save => {
+                    let filename = self.pop();
+                    prim::save(self.rt, filename, env)?
+                }
+                Op::LoadFile => {
+                    let filename = self.pop();
+                    let (mut input, path) = prim::load(self.rt, filename)?;
+                    let env = Rc::clone(env);
+                    prim::in_dir(self.rt, &path, |rt| interpretor(rt, &mut input, env))?;
+                    ValType::Boolean(true)
+                }
+                Op::SaveImage => {
+                    let filename = self.pop();
+                    prim::save_image(self.rt, filename, env)?
+                }
+                Op::LoadImage => {
+                    let filename = self.pop();
+                    prim::load_image(self.rt, filename, env)?
+                }
+                Op::Erall => prim::erall(env),
+                Op::Exit => prim::bye()?,
+
+                Op::Jump(to) => {
+                    *ip = to as usize;
+                    continue;
+                }
+                Op::JumpUnless(to) => {
+                    if !prim::condition(self.pop(), "if")? {
+                        *ip = to as usize;
+                    }
+                    continue;
+                }
+                Op::Block(block, tail) => {
+                    let block = Rc::clone(&chunk.blocks[block as usize]);
+                    self.enter(block, Rc::clone(env), tail);
+                    return Ok(Stop::Frame);
+                }
+                Op::RunList => {
+                    let block = prim::code(self.pop(), "run")?;
+                    self.enter(Rc::new(block), Rc::clone(env), false);
+                    return Ok(Stop::Frame);
+                }
+                Op::Repeat(body) => {
+                    let body = self.loop_list(chunk, body, "repeat")?;
+                    let left = prim::times(self.pop())?;
+                    self.start_loop(Looping::Repeat { body, left }, Rc::clone(env));
+                    return Ok(Stop::Frame);
+                }
+                Op::While(cond, body) => {
+                    let body = self.loop_list(chunk, body, "while")?;
+                    let cond = self.loop_list(chunk, cond, "while")?;
+                    self.start_loop(Looping::While { cond, body }, Rc::clone(env));
+                    return Ok(Stop::Frame);
+                }
+                Op::For(body) => {
+                    let body = self.loop_list(chunk, body, "for")?;
+                    let (var, steps) = prim::for_range(self.pop(), env)?;
+                    self.start_loop(Looping::For { var, steps, body }, Rc::clone(env));
+                    return Ok(Stop::Frame);
+                }
+                Op::Foreach => {
+                    let func = self.pop();
+                    let items = prim::items(self.pop())?;
+                    let (name, callee) = prim::each_callee(func, env)?;
+                    self.start_loop(
+                        Looping::Foreach {
+                            items,
+                            name,
+                            callee,
+                        },
+                        Rc::clone(env),
+                    );
+                    return Ok(Stop::Frame);
+                }
+                Op::Func(site) => {
+                    let site = &chunk.sites[site as usize];
+                    let callee = self.function(chunk, site, env)?;
+                    self.callees.push(callee);
+                    continue;
+                }
+                Op::Call(site) => {
+                    let site = &chunk.sites[site as usize];
+                    let args = self.stack.split_off(self.stack.len() - site.argc);
+                    let func = match self.callees.pop() {
+                        Some(Callee::Mua(func)) => func,
+                        Some(Callee::Host(host)) => {
+                            let res = (host.func)(&args)?;
+                            // Host calls take no frame, even in tail position
+                            self.push(if site.tail { Retv(Box::new(res)) } else { res });
+                            continue;
+                        }
+                        None => unreachable!("a call comes after its function"),
+                    };
+                    let call = TailCall {
+                        name: chunk.vars[site.var as usize].name.clone(),
+                        func,
+                        args,
+                        pos: site.pos.clone(),
+                    };
+                    if site.tail {
+                        Tail(Box::new(call))
+                    } else {
+                        prim::check_depth(self.rt)?;
+                        let global = env.borrow().get_global();
+                        self.frames.push(Activation::Call {
+                            global: Rc::clone(&global),
+                        });
+                        self.invoke(call, &global);
+                        return Ok(Stop::Frame);
+                    }
+                }
+            };
+            self.push(res);
+        }
+    }
+
+    // As prim::function, with the name looked up by its slots
+    fn function(
+        &self,
+        chunk: &Chunk,
+        site: &Site,
+        env: &Rc<RefCell<SymTable>>,
+    ) -> Result<Callee, MuaError> {
+        let var = &chunk.vars[site.var as usize];
+        let table = env.borrow();
+        if table.host(&var.name).is_some() {
+            drop(table);
+            return prim::function(&var.name, site.argc, env);
+        }
+        match find(var, &table, &chunk.scope, |func| {
+            prim::callee(&var.name, site.argc, func)
+        }) {
+            Some(callee) => callee,
+            None => prim::undefined_function(&var.name),
+        }
+    }
+
+    fn enter(&mut self, block: Rc<Block>, env: Rc<RefCell<SymTable>>, tail: bool) {
+        self.frames.push(Activation::Block(BlockRun {
+            block,
+            next: 0,
+            env,
+            tail,
+            res: ValType::Null,
+            code: None,
+            ip: 0,
+        }));
+    }
+
+    fn loop_list(
+        &mut self,
+        chunk: &Chunk,
+        block: Option<u32>,
+        op: &str,
+    ) -> Result<Rc<Block>, MuaError> {
+        match block {
+            Some(block) => Ok(Rc::clone(&chunk.blocks[block as usize])),
+            None => Ok(Rc::new(prim::code(self.pop(), op)?)),
+        }
+    }
+
+    fn start_loop(&mut self, looping: Looping, env: Rc<RefCell<SymTable>>) {
+        self.frames.push(Activation::Loop(LoopRun {
+            looping,
+            env,
+            running: None,
+        }));
+    }
+
+    fn loop_run(&mut self, at: usize) -> &mut LoopRun {
+        match &mut self.frames[at] {
+            Activation::Loop(run) => run,
+            _ => unreachable!("rounds belong to a loop"),
+        }
+    }
+
+    // The loop on the top is left with res
+    fn end_loop(&mut self, res: ValType) -> Result<(), MuaError> {
+        self.frames.pop();
+        self.push(res);
+        Ok(())
+    }
+
+    // Take what the last round gave, then start the next one or leave
+    fn next_round(&mut self) -> Result<(), MuaError> {
+        let at = self.frames.len() - 1;
+        match self.loop_run(at).running.take() {
+            // A break or return in the condition leaves too
+            Some(Part::Cond) => {
+                let res = self.pop();
+                if res.is_ret_value() {
+                    if let Some(exit) = prim::loop_exit(res) {
+                        return self.end_loop(exit);
+                    }
+                } else if !prim::condition(res, "while")? {
+                    return self.end_loop(ValType::Null);
+                } else if let LoopRun {
+                    looping: Looping::While { body, .. },
+                    env,
+                    running,
+                } = self.loop_run(at)
+                {
+                    let (body, env) = (Rc::clone(body), Rc::clone(env));
+                    *running = Some(Part::Body);
+                    self.enter(body, env, false);
+                    return Ok(());
+                }
+            }
+            Some(Part::Body) => {
+                if let Some(exit) = prim::loop_exit(self.pop()) {
+                    return self.end_loop(exit);
+                }
+            }
+            None => (),
+        }
+
+        prim::tick(self.rt)?;
+        let run = self.loop_run(at);
+        let env = Rc::clone(&run.env);
+        let (part, block) = match &mut run.looping {
+            Looping::Repeat { body, left } if *left > 0 => {
+                *left -= 1;
+                (Part::Body, Rc::clone(body))
+            }
+            Looping::While { cond, .. } => (Part::Cond, Rc::clone(cond)),
+            Looping::For { var, steps, body } => match steps.next() {
+                Some(n) => {
+                    prim::make(var.clone(), Num(n.into()), &env);
+                    (Part::Body, Rc::clone(body))
+                }
+                None => return self.end_loop(ValType::Null),
+            },
+            Looping::Foreach {
+                items,
+                name,
+                callee,
+            } => {
+                let item = match items.pop_front() {
+                    Some(item) => item,
+                    None => return self.end_loop(ValType::Null),
+                };
+                let (name, callee) = (name.clone(), callee.clone());
+                run.running = Some(Part::Body);
+                match callee {
+                    Callee::Host(host) => {
+                        let res = (host.func)(&[item])?;
+                        self.push(res);
+                    }
+                    Callee::Mua(func) => {
+                        prim::check_depth(self.rt)?;
+                        let global = env.borrow().get_global();
+                        self.frames.push(Activation::Call {
+                            global: Rc::clone(&global),
+                        });
+                        let call = TailCall {
+                            name,
+                            func,
+                            args: vec![item],
+                            pos: None,
+                        };
+                        self.invoke(call, &global);
+                    }
+                }
+                return Ok(());
+            }
+            Looping::Repeat { .. } => return self.end_loop(ValType::Null),
+        };
+        run.running = Some(part);
+        self.enter(block, env, false);
+        Ok(())
+    }
+
+    // Start the body of a function
+    fn invoke(&mut self, call: TailCall, global: &Rc<RefCell<SymTable>>) {
+        let (body, cenv) = prim::bind(call.func, &call.args, global);
+        self.rt.stack.push(Frame {
+            name: call.name,
+            args: call.args,
+            pos: call.pos,
+        });
+        self.enter(body, cenv, true);
+    }
+
+    // A list is done, its value goes to whoever ran it
+    fn leave(&mut self, tail: bool, res: ValType) {
+        let res = if tail {
+            res
+        } else {
+            prim::leave_list(self.rt, res)
+        };
+        self.push(res);
+    }
+
+    // A function body is done, make the call it returned or go back
+    fn ret(&mut self, res: ValType) -> Result<(), MuaError> {
+        self.rt.stack.pop();
+        match res {
+            Tail(box next) => {
+                let global = match self.frames.last() {
+                    Some(Activation::Call { global }) => Rc::clone(global),
+                    _ => unreachable!(),
+                };
+                self.invoke(next, &global);
+            }
+            Retv(box res) | res => {
+                let res = prim::outside_loop(res)?;
+                self.frames.pop();
+                self.push(res);
+            }
+        }
+        Ok(())
+    }
+
+    fn unwind(&mut self, mut e: MuaError) -> MuaError {
+        for frame in self.frames.iter().rev() {
+            let (chunk, ip) = match frame {
+                Activation::Exec { chunk, ip, .. } => (chunk, ip),
+                Activation::Block(BlockRun {
+                    code: Some(chunk),
+                    ip,
+                    ..
+                }) => (chunk, ip),
+                _ => continue,
+            };
+            if let Some(pos) = chunk.pos_at(ip - 1) {
+                e = e.at(&Some(pos.clone()));
+            }
+        }
+        // Only the innermost call sees the whole stack
+        if e.backtrace.is_empty() && self.rt.stack.len() > self.depth {
+            e.backtrace = self.rt.stack.iter().rev().cloned().collect();
+        }
+        self.rt.stack.truncate(self.depth);
+        self.frames.clear();
+        e
+    }
+}
@@ -0,0 +1,685 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-11 11:05:12
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-11 11:05:12
+ * @Description: The embedding API, on both engines
+ * @Copyright: Copyright (c) 2022
+ */
+
+use minter::{Capture, Engine, ErrorKind, Files, Input, Interpreter, Limits, MuaError, ValType};
+use std::fs;
+use std::io::Cursor;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+const ENGINES: [Engine; 2] = [Engine::Tree, Engine::Vm];
+
+fn interpreter(engine: Engine) -> Interpreter {
+    let mut interp = Interpreter::new();
+    interp.set_engine(engine);
+    interp
+}
+
+fn num(n: f64) -> ValType {
+    ValType::Num(n.into())
+}
+
+#[test]
+fn eval_returns_last_value() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        assert_eq!(interp.eval_str("make \"x 2 mul :x 21"), Ok(num(42.0)));
+        assert_eq!(interp.get_var("x"), Ok(num(2.0)));
+        assert_eq!(interp.eval_str(""), Ok(ValType::Null));
+    }
+}
+
+#[test]
+fn vars_are_shared_with_code() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        assert_eq!(interp.set_var("n", num(5.0)), Ok(num(5.0)));
+        assert_eq!(interp.eval_str("add :n 1"), Ok(num(6.0)));
+        assert!(interp.get_var("pi").is_ok());
+
+        let err = interp.get_var("missing").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedName);
+        let err = interp.set_var("not a name", num(1.0)).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidName);
+    }
+}
+
+#[test]
+fn call_function_keeps_closures() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp
+            .eval_str(
+                "make \"adder [[x] [return [[y] [return add :x :y]]]]
+                 make \"add3 adder 3",
+            )
+            .unwrap();
+        assert_eq!(interp.call_function("add3", vec![num(4.0)]), Ok(num(7.0)));
+
+        let err = interp.call_function("add3", vec![]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::ArityError);
+        let err = interp.call_function("nothing", vec![]).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedName);
+    }
+}
+
+#[test]
+fn errors_leave_the_session_usable() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.eval_str("make \"f [[x] [return div 1 :x]]").unwrap();
+        let err = interp.eval_str("f 0").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::DivideByZero);
+        assert_eq!(err.backtrace.len(), 1);
+        assert_eq!(interp.eval_str("f 4"), Ok(num(0.25)));
+    }
+}
+
+// Each error is returned with its kind, nothing is printed or left
+#[test]
+fn errors_are_results() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let capture = Capture::default();
+        interp.set_output(Box::new(capture.clone()));
+        interp
+            .eval_str("make \"f [[] [break]] make \"g [[x] [return :x]]")
+            .unwrap();
+        for (code, kind) in [
+            ("print :missing", ErrorKind::UndefinedName),
+            ("print missing", ErrorKind::UndefinedName),
+            ("add \"one 1", ErrorKind::TypeMismatch),
+            ("if 1 [] []", ErrorKind::TypeMismatch),
+            ("div 1 0", ErrorKind::DivideByZero),
+            ("print [1 2", ErrorKind::ParseError),
+            ("print add 1", ErrorKind::ArityError),
+            ("run [g]", ErrorKind::ArityError),
+            ("break", ErrorKind::FlowError),
+            ("repeat 2 [f]", ErrorKind::FlowError),
+            ("print 1 exit print 2", ErrorKind::Exit),
+        ] {
+            assert_eq!(
+                interp.eval_str(code).map_err(|e| e.kind),
+                Err(kind),
+                "{}",
+                code
+            );
+        }
+        assert_eq!(capture.out.take(), "1\n");
+        assert_eq!(capture.err.take(), "");
+        assert_eq!(interp.eval_str("g 5"), Ok(num(5.0)));
+    }
+}
+
+// Errors point at the word of the innermost operation which failed
+#[test]
+fn errors_tell_where() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let code = "make \"f [[x] [\n  return add 1 :x\n]]\nprint f \"a\n";
+        let err = interp
+            .eval(&mut Input::named(Cursor::new(code), "t.mua"))
+            .unwrap_err();
+        let pos = err.pos.unwrap();
+        assert_eq!((&*pos.file, pos.line, pos.col), ("t.mua", 2, 10));
+        assert_eq!(err.backtrace[0].pos.as_ref().unwrap().line, 4);
+
+        let err = interp
+            .eval(&mut Input::named(
+                Cursor::new("print 1\n  print :nope"),
+                "u.mua",
+            ))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "u.mua:2:9: undefined name \"nope\"");
+
+        // Code built at runtime has no position of its own
+        let err = interp.eval_str("run list \"print \":nope").unwrap_err();
+        assert_eq!(err.pos, None);
+    }
+}
+
+// A backtrace has the calls on the way to an error, innermost first,
+// with their arguments and where they were made
+#[test]
+fn errors_carry_backtraces() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let capture = Capture::default();
+        interp.set_output(Box::new(capture.clone()));
+        let code = "make \"inner [[x] [return div 1 :x]]
+make \"outer [[x] [return add 1 inner sub :x 1]]
+make \"down [[n] [if eq :n 0 [return div 1 0] [return add 1 down sub :n 1]]]
+print outer 1";
+        let err = interp
+            .eval(&mut Input::named(Cursor::new(code), "t.mua"))
+            .unwrap_err();
+        let frames: Vec<_> = err.backtrace.iter().map(|f| f.to_string()).collect();
+        assert_eq!(
+            frames,
+            ["inner 0 at t.mua:2:32", "outer 1 at t.mua:4:7"],
+            "{:?}",
+            engine
+        );
+        interp.report(&err).unwrap();
+        assert_eq!(
+            capture.err.take(),
+            "t.mua:1:26: Divide By Zero - div by zero
+Backtrace (most recent call first):
+   0: inner 0 at t.mua:2:32
+   1: outer 1 at t.mua:4:7
+"
+        );
+
+        // Deep ones are cut short
+        let err = interp.eval_str("down 30").unwrap_err();
+        assert_eq!(err.backtrace.len(), 31);
+        interp.report(&err).unwrap();
+        let report = capture.err.take();
+        assert!(
+            report.ends_with("  19: down 19 at t.mua:3:60\n      ... 11 more\n"),
+            "{}",
+            report
+        );
+
+        // Errors out of any function have none
+        assert!(interp.eval_str("div 1 0").unwrap_err().backtrace.is_empty());
+    }
+}
+
+// Code in a list is grouped the first time it runs, and again only when
+// an arity it was grouped with has changed
+#[test]
+fn list_code_is_regrouped_when_arities_change() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp
+            .eval_str("make \"h [[x] [return :x]] make \"f [[] [return h 5 6]]")
+            .unwrap();
+        assert_eq!(interp.eval_str("f"), Ok(num(5.0)));
+        assert_eq!(interp.eval_str("f"), Ok(num(5.0)));
+        interp
+            .eval_str("make \"h [[x y] [return add :x :y]]")
+            .unwrap();
+        assert_eq!(interp.eval_str("f"), Ok(num(11.0)));
+        interp.eval_str("make \"h [[] [return 2]]").unwrap();
+        assert_eq!(interp.eval_str("f"), Ok(num(2.0)));
+    }
+}
+
+// Grouping a statement reads only its own tokens, long bodies take time
+// in proportion to their length
+#[test]
+fn long_bodies_are_grouped_in_linear_time() {
+    let mut code = String::from("make \"f [[n] [\n");
+    for _ in 0..20000 {
+        code.push_str("  make \"n add :n 1\n");
+    }
+    code.push_str("  return :n\n]]\n");
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.eval_str(&code).unwrap();
+        let start = Instant::now();
+        assert_eq!(interp.eval_str("f 0"), Ok(num(20000.0)));
+        assert!(start.elapsed() < Duration::from_secs(10), "{:?}", engine);
+    }
+}
+
+fn assert_eq_host(interp: &mut Interpreter) {
+    interp
+        .register("assert_eq", 2, |args| {
+            if args[0] == args[1] {
+                Ok(ValType::Boolean(true))
+            } else {
+                Err(MuaError::new(
+                    ErrorKind::HostError,
+                    format!("{} is not {}", args[0], args[1]),
+                ))
+            }
+        })
+        .unwrap();
+}
+
+#[test]
+fn host_functions_group_and_dispatch() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        assert_eq_host(&mut interp);
+        interp.register("timestamp", 0, |_| Ok(num(7.0))).unwrap();
+
+        assert_eq!(
+            interp.eval_str("assert_eq add 1 2 3"),
+            Ok(ValType::Boolean(true))
+        );
+        assert_eq!(interp.eval_str("add timestamp 1"), Ok(num(8.0)));
+        interp
+            .eval_str("make \"f [[x] [return assert_eq :x timestamp]]")
+            .unwrap();
+        assert_eq!(interp.eval_str("f 7"), Ok(ValType::Boolean(true)));
+        assert_eq!(
+            interp.call_function("assert_eq", vec![num(1.0), num(1.0)]),
+            Ok(ValType::Boolean(true))
+        );
+
+        let err = interp.eval_str("f 8").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::HostError);
+        assert_eq!(err.backtrace.len(), 1);
+
+        let err = interp
+            .register("make", 2, |_| Ok(ValType::Null))
+            .unwrap_err();
+        assert_eq!(err.kind, ErrorKind::InvalidName);
+    }
+}
+
+#[test]
+fn output_is_captured() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let capture = Capture::default();
+        interp.set_output(Box::new(capture.clone()));
+
+        interp
+            .eval_str("make \"f [[x] [print :x return div 1 :x]] print f 2")
+            .unwrap();
+        assert_eq!(capture.out.take(), "2\n0.5\n");
+        assert_eq!(capture.out.take(), "");
+
+        let err = interp.eval_str("f 0").unwrap_err();
+        interp.report(&err).unwrap();
+        assert_eq!(capture.out.take(), "0\n");
+        assert_eq!(
+            capture.err.take(),
+            "Divide By Zero - div by zero\nBacktrace (most recent call first):\n   0: f 0\n"
+        );
+    }
+}
+
+#[test]
+fn read_takes_data_not_code() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.set_input(Input::reader(Cursor::new("fortytwo\none two\n")));
+
+        // The words after read are code, they are not read
+        assert_eq!(
+            interp.eval_str("make \"a read make \"b readlist :a"),
+            Ok(ValType::Str("fortytwo".to_string()))
+        );
+        assert_eq!(
+            interp.eval_str("last :b"),
+            Ok(ValType::Str("two".to_string()))
+        );
+
+        let err = interp.eval_str("read").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IoError);
+    }
+}
+
+// Each interpreter draws from its own seed, and seeds give their own
+// sequences
+#[test]
+fn seeds_fix_random() {
+    let draws = |seed| {
+        let mut interp = Interpreter::new();
+        interp.set_seed(seed);
+        interp
+            .eval_str("list random 1000 list random 1000 random 1000")
+            .unwrap()
+    };
+    assert_eq!(draws(7), draws(7));
+    let seeds: Vec<_> = (0..4).map(draws).collect();
+    for (i, a) in seeds.iter().enumerate() {
+        for b in &seeds[i + 1..] {
+            assert_ne!(a, b);
+        }
+    }
+
+    // Draws of one don't move the other on
+    let mut a = Interpreter::new();
+    let mut b = Interpreter::new();
+    a.set_seed(1);
+    b.set_seed(1);
+    let first = a.eval_str("random 1000").unwrap();
+    a.eval_str("random 1000").unwrap();
+    assert_eq!(b.eval_str("random 1000").unwrap(), first);
+}
+
+#[test]
+fn limits_stop_runaway_code() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.set_limits(Limits {
+            steps: Some(10000),
+            depth: 50,
+            size: Some(100),
+        });
+        interp
+            .eval_str(
+                "make \"spin [[] [return spin]]
+                 make \"deep [[n] [if eq :n 0 [return 0] [return add 1 deep sub :n 1]]]
+                 make \"grow [[l] [return grow sentence :l :l]]",
+            )
+            .unwrap();
+
+        assert_eq!(
+            interp.eval_str("spin").unwrap_err().kind,
+            ErrorKind::StepLimit
+        );
+        assert_eq!(
+            interp.eval_str("deep 100").unwrap_err().kind,
+            ErrorKind::RecursionLimit
+        );
+        assert_eq!(
+            interp.eval_str("grow [a]").unwrap_err().kind,
+            ErrorKind::SizeLimit
+        );
+        interp
+            .eval_str("make \"w \"abcdefghijklmnopqrstuvwxy")
+            .unwrap();
+        let err = interp.eval_str("make \"w word :w :w make \"w word :w :w make \"w word :w :w");
+        assert_eq!(err.unwrap_err().kind, ErrorKind::SizeLimit);
+        assert_eq!(interp.get_var("w").unwrap().to_string().len(), 100);
+
+        // Sizes add up from what a list is built of: each item counts
+        // itself and what's in it
+        let err = interp.eval_str("make \"l [] repeat 60 [make \"l join :l 1]");
+        assert_eq!(err.unwrap_err().kind, ErrorKind::SizeLimit);
+        match interp.get_var("l").unwrap() {
+            ValType::List(items, _) => assert_eq!(items.len(), 50),
+            l => panic!("{}", l),
+        }
+        let err = interp.eval_str("make \"l butlast :l make \"m list :l [a]");
+        assert_eq!(err.unwrap_err().kind, ErrorKind::SizeLimit);
+        interp
+            .eval_str("make \"l butlast :l make \"m list :l [a]")
+            .unwrap();
+
+        // The budget is per eval, the session goes on
+        assert_eq!(interp.eval_str("add 1 2"), Ok(num(3.0)));
+        assert_eq!(interp.eval_str("deep 40"), Ok(num(40.0)));
+        assert_eq!(interp.eval_str("deep 40"), Ok(num(40.0)));
+    }
+}
+
+// return f ... as the last thing a function does takes no stack, on a
+// small one too
+#[test]
+fn tail_calls_run_in_place() {
+    let run = || {
+        for engine in ENGINES {
+            let mut interp = interpreter(engine);
+            interp
+                .eval_str(
+                    "make \"count [[n acc] [if eq :n 0 [return :acc] [return count sub :n 1 add :acc 1]]]",
+                )
+                .unwrap();
+            assert_eq!(interp.eval_str("count 1000000 0"), Ok(num(1e6)));
+        }
+    };
+    std::thread::Builder::new()
+        .stack_size(1 << 20)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+// The tree engine nests MUA calls on the native stack. On a thread with
+// a small one, going deep is an error and not a crash
+#[test]
+fn deep_calls_fit_any_stack() {
+    let run = || {
+        let define = "make \"deep [[n] [if eq :n 0 [return 0] [return add 1 deep sub :n 1]]]
+             make \"again [run :again]";
+        let mut tree = interpreter(Engine::Tree);
+        tree.eval_str(define).unwrap();
+        for code in ["deep 5000", "run :again"] {
+            let err = tree.eval_str(code).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::RecursionLimit, "{}", code);
+        }
+        assert_eq!(tree.eval_str("deep 20"), Ok(num(20.0)));
+
+        // The vm doesn't nest them
+        let mut vm = interpreter(Engine::Vm);
+        vm.eval_str(define).unwrap();
+        assert_eq!(vm.eval_str("deep 5000"), Ok(num(5000.0)));
+    };
+    std::thread::Builder::new()
+        .stack_size(1 << 20)
+        .spawn(run)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn interrupts_stop_a_run() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.eval_str("make \"spin [[] [return spin]]").unwrap();
+
+        // Set before the run, it's dropped
+        let interrupt = interp.interrupt();
+        interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(interp.eval_str("add 1 2"), Ok(num(3.0)));
+
+        let stopper = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        let err = interp.eval_str("spin").unwrap_err();
+        stopper.join().unwrap();
+        assert_eq!(err.kind, ErrorKind::Interrupted);
+        assert_eq!(interp.eval_str("add 1 2"), Ok(num(3.0)));
+    }
+}
+
+// Bytes which aren't utf8 are taken as U+FFFD, the rest still runs
+#[test]
+fn bad_bytes_are_replaced() {
+    let mut interp = Interpreter::new();
+    let mut input = Input::reader(Cursor::new(b"make \"w \"a\xff\nmake \"n 1\n".to_vec()));
+    interp.eval(&mut input).unwrap();
+    assert_eq!(
+        interp.get_var("w").unwrap(),
+        ValType::Str("a\u{fffd}".into())
+    );
+    assert_eq!(interp.get_var("n").unwrap(), num(1.0));
+}
+
+#[test]
+fn files_stay_in_the_sandbox() {
+    for engine in ENGINES {
+        let dir = std::env::temp_dir().join(format!("minter-{}-{:?}", std::process::id(), engine));
+        fs::create_dir_all(&dir).unwrap();
+        let mut interp = interpreter(engine);
+        interp.set_files(Files::Within(dir.clone()));
+
+        interp.eval_str("make \"x 1 save \"vars.mua erall").unwrap();
+        assert!(dir.join("vars.mua").exists());
+        interp.eval_str("load \"./vars.mua").unwrap();
+        assert_eq!(interp.get_var("x"), Ok(num(1.0)));
+
+        for name in ["../vars.mua", "/etc/passwd", "a/../../vars.mua", ""] {
+            let err = interp.eval_str(&format!("load \"{}", name)).unwrap_err();
+            assert_eq!(err.kind, ErrorKind::AccessDenied, "{}", name);
+            let err = interp
+                .eval_str(&format!("loadimage \"{}", name))
+                .unwrap_err();
+            assert_eq!(err.kind, ErrorKind::AccessDenied, "{}", name);
+        }
+        #[cfg(unix)]
+        {
+            let link = dir.join("out");
+            let _ = fs::remove_file(&link);
+            std::os::unix::fs::symlink("/etc", &link).unwrap();
+            let err = interp.eval_str("load \"out/passwd").unwrap_err();
+            assert_eq!(err.kind, ErrorKind::AccessDenied);
+        }
+
+        interp.set_files(Files::Denied);
+        let err = interp.eval_str("save \"vars.mua").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::AccessDenied);
+        let err = interp.eval_str("saveimage \"vars.img").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::AccessDenied);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+#[test]
+fn files_are_parsed_before_running() {
+    let dir = std::env::temp_dir().join(format!("minter-parse-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let file = |name: &str, code: &str| {
+        let path = dir.join(name);
+        fs::write(&path, code).unwrap();
+        path.to_string_lossy().into_owned()
+    };
+
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let capture = Capture::default();
+        interp.set_output(Box::new(capture.clone()));
+
+        // Every error is found, nothing runs
+        let bad = file(
+            "bad.mua",
+            "print 1\nprint 1.2.3\nmake \"f [[x] [return add :x]]\nprint ]\nprint nope\n",
+        );
+        let program = interp.parse_file(&bad).unwrap();
+        let lines: Vec<_> = program
+            .errors()
+            .iter()
+            .map(|err| err.pos.as_ref().unwrap().line)
+            .collect();
+        assert_eq!(lines, [2, 3, 4, 5]);
+        assert_eq!(interp.eval_file(&bad).unwrap_err(), program.errors()[0]);
+        assert_eq!(capture.out.take(), "");
+
+        // Functions made from literals group from their make, a body
+        // may call one made after it
+        let ok = file(
+            "ok.mua",
+            "make \"twice [[x] [return double double :x]]\nmake \"double [[x] [return mul :x 2]]\nprint twice 3\n",
+        );
+        let program = interp.parse_file(&ok).unwrap();
+        assert!(program.errors().is_empty());
+        assert_eq!(program.len(), 3);
+        assert!(program.deferred().is_none());
+        assert_eq!(interp.run_program(&program), Ok(num(12.0)));
+        assert_eq!(capture.out.take(), "12\n");
+
+        // A function made as the file runs leaves the rest to then
+        let later = file(
+            "later.mua",
+            "make \"adder [[x] [return [[y] [return add :x :y]]]]\nmake \"add2 adder 2\nprint add2 5\n",
+        );
+        let program = interp.parse_file(&later).unwrap();
+        assert!(program.errors().is_empty());
+        let (name, pos) = program.deferred().unwrap();
+        assert_eq!((name.as_str(), pos.as_ref().unwrap().line), ("add2", 3));
+        assert_eq!(interp.run_program(&program), Ok(num(7.0)));
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// Grouping a file reads each statement's tokens once, a long one takes
+// time in proportion to its length
+#[test]
+fn long_files_are_grouped_in_linear_time() {
+    let dir = std::env::temp_dir().join(format!("minter-long-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("long.mua");
+    let mut code = String::from("make \"inc [[x] [return add :x 1]]\nmake \"n 0\n");
+    for _ in 0..16000 {
+        code.push_str("make \"n inc :n\n");
+    }
+    code.push_str("print :n\n");
+    fs::write(&path, code).unwrap();
+
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        let capture = Capture::default();
+        interp.set_output(Box::new(capture.clone()));
+        let start = Instant::now();
+        let program = interp.parse_file(path.to_str().unwrap()).unwrap();
+        assert!(program.errors().is_empty());
+        assert_eq!(program.len(), 16003);
+        interp.run_program(&program).unwrap();
+        assert!(start.elapsed() < Duration::from_secs(10), "{:?}", engine);
+        assert_eq!(capture.out.take(), "16000\n");
+    }
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn the_session_can_be_looked_at() {
+    let mut interp = Interpreter::new();
+    interp
+        .eval_str("make \"x 1 make \"f [[a b] [return add :a :b]]")
+        .unwrap();
+    let mut funcs: Vec<_> = interp
+        .globals()
+        .functions()
+        .map(|(name, arity)| (name.clone(), *arity))
+        .collect();
+    funcs.sort();
+    assert_eq!(funcs, [("f".to_string(), 2)]);
+    assert_eq!(interp.globals().get_keys_values().count(), 3);
+    assert_eq!(
+        interp.source("f").unwrap(),
+        "make \"f [[a b] [return add :a :b]]\n"
+    );
+    assert_eq!(
+        interp.source("nope").unwrap_err().kind,
+        ErrorKind::UndefinedName
+    );
+
+    // Grouped with the arities known, nothing runs
+    let mut input = Input::string("print f 1 mul 2 :x make \"x 2");
+    assert_eq!(
+        interp.parse_next(&mut input).unwrap().unwrap(),
+        "(print (f 1 (mul 2 :x)))"
+    );
+    assert_eq!(
+        interp.parse_next(&mut input).unwrap().unwrap(),
+        "(make \"x 2)"
+    );
+    assert_eq!(interp.parse_next(&mut input).unwrap(), None);
+    assert_eq!(interp.get_var("x").unwrap(), num(1.0));
+
+    interp.reset();
+    assert!(interp.get_var("x").is_err());
+    assert!(interp.globals().functions().next().is_none());
+    assert!(interp.get_var("pi").is_ok());
+}
+
+// A function name made a value isn't called any more
+#[test]
+fn rebound_functions_are_no_operations() {
+    for engine in ENGINES {
+        let mut interp = interpreter(engine);
+        interp.eval_str("make \"f [[x] [return :x]]").unwrap();
+        assert!(interp.operations().contains(&"f".to_string()));
+        interp.eval_str("make \"f 1").unwrap();
+        assert!(!interp.operations().contains(&"f".to_string()));
+        assert!(interp.globals().functions().next().is_none());
+        let err = interp.eval_str("print f 2").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::UndefinedName);
+
+        // The same from a function's own table
+        interp
+            .eval_str("make \"g [[] [make \"h [[] [return 1]] make \"h 2 return h]]")
+            .unwrap();
+        assert_eq!(
+            interp.eval_str("g").unwrap_err().kind,
+            ErrorKind::UndefinedName
+        );
+    }
+}
@@ -0,0 +1,341 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-10 10:21:48
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-10 10:21:48
+ * @Description: Run tests/mua with both engines, they must agree
+ * @Copyright: Copyright (c) 2022
+ */
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+
+// Scripts read from a .in file next to them, if there's one
+fn run(engine: &str, script: &Path) -> Output {
+    let mut cmd = Command::new(env!("CARGO_BIN_EXE_MInter"));
+    cmd.current_dir(env!("CARGO_MANIFEST_DIR")).args([
+        &format!("--engine={}", engine),
+        "--seed",
+        "1",
+    ]);
+    let data = script.with_extension("in");
+    if Path::new(env!("CARGO_MANIFEST_DIR")).join(&data).exists() {
+        cmd.arg("--stdin-file").arg(data);
+    }
+    cmd.arg(script).output().unwrap()
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/mua");
+    let mut scripts: Vec<_> = fs::read_dir(&dir)
+        .expect("tests/mua not found")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "mua"))
+        .map(|path| {
+            path.strip_prefix(env!("CARGO_MANIFEST_DIR"))
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+#[test]
+fn engines_agree() {
+    for script in scripts() {
+        let tree = run("tree", &script);
+        let vm = run("vm", &script);
+        let name = script.display();
+
+        // Errors are part of the output, positions and backtraces too
+        assert_eq!(
+            String::from_utf8_lossy(&tree.stdout),
+            String::from_utf8_lossy(&vm.stdout),
+            "{}: stdout differs",
+            name
+        );
+        assert_eq!(
+            String::from_utf8_lossy(&tree.stderr),
+            String::from_utf8_lossy(&vm.stderr),
+            "{}: stderr differs",
+            name
+        );
+        assert_eq!(
+            tree.status.code(),
+            vm.status.code(),
+            "{}: status differs",
+            name
+        );
+
+        let expected = fs::read_to_string(
+            Path::new(env!("CARGO_MANIFEST_DIR")).join(script.with_extension("out")),
+        )
+        .unwrap_or_else(|_| panic!("{}: no .out file", name));
+        assert_eq!(String::from_utf8_lossy(&tree.stdout), expected, "{}", name);
+        assert_eq!(
+            tree.status.success(),
+            !script
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("err_"),
+            "{}: only err_ scripts should fail",
+            name
+        );
+    }
+}
+
+// The console and read take turns at stdin, a line each
+#[test]
+fn repl_shares_stdin_with_read() {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_MInter"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(b"print add read 1\n41\nprint readlist\na b\n")
+        .unwrap();
+    let out = repl.wait_with_output().unwrap();
+    let out = String::from_utf8_lossy(&out.stdout);
+    assert!(out.contains("42\n") && out.contains("a b\n"), "{}", out);
+}
+
+// Commands start with ',' and take the rest of their line
+#[test]
+fn repl_commands_inspect_the_session() {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_MInter"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(
+            b"make \"x [1 2] make \"f [[a] [return :a]]\n,vars\n,funcs\n,show f\n\
+              ,ast print f add 1 2\n,time print 3\n,reset\n,vars\n\
+              ,load tests/mua/err_syntax.mua\n,show\n,nope\n",
+        )
+        .unwrap();
+    let out = repl.wait_with_output().unwrap();
+    let stdout = String::from_utf8_lossy(&out.stdout).replace("User>", "");
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    // Pipes aren't terminals, nothing gets colored
+    assert!(!stdout.contains('\u{1b}') && !stderr.contains('\u{1b}'));
+    let lines: Vec<_> = stdout.lines().collect();
+    assert_eq!(
+        lines[..6],
+        [
+            "pi = 3.14159",
+            "x = [1 2]",
+            "f/1",
+            "make \"f [[a] [return :a]]",
+            "(print (f (add 1 2)))",
+            "3",
+        ]
+    );
+    assert!(lines[6].starts_with("time: "), "{}", stdout);
+    assert_eq!(lines[7..], ["pi = 3.14159"]);
+    assert!(stderr.contains("err_syntax.mua:"), "{}", stderr);
+    assert!(stderr.contains(",show expects a name"), "{}", stderr);
+    assert!(stderr.contains("unknown command ,nope"), "{}", stderr);
+}
+
+// Lines typed at a terminal, one at a time as the editor takes them. The
+// output is without colors and cursor moves
+#[cfg(target_os = "linux")]
+fn typed(lines: &[&str]) -> Option<String> {
+    let home = std::env::temp_dir().join(format!("minter-tty-{}", std::process::id()));
+    fs::create_dir_all(&home).unwrap();
+    // script runs the repl on a terminal of its own
+    let mut tty = Command::new("script")
+        .args(["-qec", env!("CARGO_BIN_EXE_MInter"), "/dev/null"])
+        .env("TERM", "xterm")
+        .env("HOME", &home)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .ok()?;
+    let mut stdin = tty.stdin.take().unwrap();
+    for line in lines {
+        std::thread::sleep(std::time::Duration::from_millis(300));
+        stdin.write_all(line.as_bytes()).unwrap();
+        stdin.write_all(b"\r").unwrap();
+    }
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    drop(stdin);
+    let out = tty.wait_with_output().unwrap();
+    fs::remove_dir_all(&home).unwrap();
+
+    let mut plain = String::new();
+    let mut chars = String::from_utf8_lossy(&out.stdout).into_owned();
+    chars.retain(|c| c != '\r');
+    let mut chars = chars.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // ESC [ params letter
+            chars.find(|c| c.is_ascii_alphabetic());
+        } else {
+            plain.push(c);
+        }
+    }
+    Some(plain)
+}
+
+// Tab completes what the session has now, a statement going on asks for
+// more with ....>
+#[cfg(target_os = "linux")]
+#[test]
+fn terminal_completes_and_continues() {
+    let out = match typed(&[
+        "make \"five 5",
+        "print :fi\t",
+        "make \"foo [[] [return 1]]",
+        "make \"foo 2",
+        "fo\t[i 1 2 1] [print :i]",
+        "print add 1",
+        "2",
+    ]) {
+        Some(out) => out,
+        // No script to give it a terminal
+        None => return,
+    };
+    assert!(out.contains("User>print :five \n5\n"), "{}", out);
+    assert!(
+        out.contains("User>for [i 1 2 1] [print :i]\n1\n2\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains("User>print add 1\n....>2\n3\n"), "{}", out);
+}
+
+// ,funcs lists what is a function now
+#[test]
+fn repl_forgets_rebound_functions() {
+    let mut repl = Command::new(env!("CARGO_BIN_EXE_MInter"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    repl.stdin
+        .take()
+        .unwrap()
+        .write_all(b"make \"f [[x] [return :x]]\nmake \"g [[] []]\n,funcs\nmake \"f 1\n,funcs\n")
+        .unwrap();
+    let out = repl.wait_with_output().unwrap();
+    let out = String::from_utf8_lossy(&out.stdout).replace("User>", "");
+    let lines: Vec<_> = out.lines().collect();
+    assert_eq!(lines, ["f/1", "g/0", "g/0"], "{}", out);
+}
+
+// --check reports syntax errors and runs nothing
+#[test]
+fn check_only_parses() {
+    let check = |script: &str| {
+        Command::new(env!("CARGO_BIN_EXE_MInter"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(["--check", script])
+            .output()
+            .unwrap()
+    };
+    let ok = check("tests/mua/funcs.mua");
+    assert!(ok.status.success());
+    assert!(ok.stdout.is_empty());
+
+    let bad = check("tests/mua/err_syntax.mua");
+    assert_eq!(bad.status.code(), Some(1));
+    assert!(bad.stdout.is_empty());
+    let err = String::from_utf8_lossy(&bad.stderr);
+    assert!(err.contains(":2:7:") && err.contains(":3:14:"), "{}", err);
+}
+
+// Bad flags are reported like any other command line mistake
+#[test]
+fn max_depth_is_capped() {
+    let run = |depth: &str| {
+        Command::new(env!("CARGO_BIN_EXE_MInter"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(["--max-depth", depth, "tests/mua/funcs.mua"])
+            .output()
+            .unwrap()
+    };
+    let ok = run("20000");
+    assert!(
+        ok.status.success(),
+        "{}",
+        String::from_utf8_lossy(&ok.stderr)
+    );
+
+    let bad = run("100000000000");
+    assert_eq!(bad.status.code(), Some(1));
+    let err = String::from_utf8_lossy(&bad.stderr);
+    assert!(
+        err.contains("--max-depth expects at most 100000"),
+        "{}",
+        err
+    );
+}
+
+// Each flag tells what it takes, what it names failing is an error like
+// any other
+#[test]
+fn flag_errors_are_specific() {
+    let cli = |args: &[&str]| {
+        let out = Command::new(env!("CARGO_BIN_EXE_MInter"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(args)
+            .output()
+            .unwrap();
+        assert_eq!(out.status.code(), Some(1), "{:?}", args);
+        String::from_utf8_lossy(&out.stderr).into_owned()
+    };
+    assert_eq!(
+        cli(&["--seed", "x"]),
+        "--seed expects an unsigned integer\n"
+    );
+    assert_eq!(
+        cli(&["--max-steps"]),
+        "--max-steps expects a number of steps\n"
+    );
+    assert_eq!(cli(&["--image"]), "--image expects a file\n");
+    assert_eq!(cli(&["--files-in"]), "--files-in expects a directory\n");
+    assert_eq!(cli(&["--engine=jit"]), "--engine expects vm or tree\n");
+    let err = cli(&["--image", "tests/mua/funcs.mua", "tests/mua/funcs.mua"]);
+    assert_eq!(err, "IO Error - --image: not an image\n");
+}
+
+// What --check finds is what a run stops at before running anything
+#[test]
+fn check_agrees_with_run() {
+    let cli = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_MInter"))
+            .current_dir(env!("CARGO_MANIFEST_DIR"))
+            .args(args)
+            .output()
+            .unwrap()
+    };
+    for script in [
+        "tests/mua/forward.mua",
+        "tests/mua/err_forward.mua",
+        "tests/mua/rebind.mua",
+        "tests/mua/err_rebind.mua",
+    ] {
+        let check = cli(&["--check", script]);
+        let run = cli(&[script]);
+        assert_eq!(check.status.code(), run.status.code(), "{}", script);
+        assert_eq!(
+            String::from_utf8_lossy(&check.stderr),
+            String::from_utf8_lossy(&run.stderr),
+            "{}",
+            script
+        );
+    }
+}
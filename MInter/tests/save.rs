@@ -0,0 +1,223 @@
+/*
+ * @Author: Yinwhe
+ * @Date: 2022-03-13 18:12:40
+ * @LastEditors: Yinwhe
+ * @LastEditTime: 2022-03-13 18:12:40
+ * @Description: What save and saveimage write, load and loadimage read back the same
+ * @Copyright: Copyright (c) 2022
+ */
+
+use minter::{Engine, ErrorKind, Files, Interpreter, ListType, ValType};
+use proptest::prelude::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const ENGINES: [Engine; 2] = [Engine::Tree, Engine::Vm];
+
+fn dir(test: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("minter-{}-{}", test, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn interpreter(engine: Engine, dir: &Path) -> Interpreter {
+    let mut interp = Interpreter::new();
+    interp.set_engine(engine);
+    interp.set_files(Files::Within(dir.to_path_buf()));
+    interp
+}
+
+fn list(vals: Vec<ValType>) -> ValType {
+    ValType::List(vals.into(), ListType::Ordinary)
+}
+
+// Any word, number, bool and lists of them
+fn value() -> impl Strategy<Value = ValType> {
+    let leaf = prop_oneof![
+        any::<f64>().prop_map(|n| ValType::Num(n.into())),
+        any::<String>().prop_map(ValType::Str),
+        "[a-z\\[\\]\" \\\\:/]{0,6}".prop_map(ValType::Str),
+        any::<bool>().prop_map(ValType::Boolean),
+    ];
+    leaf.prop_recursive(3, 24, 4, |inner| {
+        prop::collection::vec(inner, 0..4).prop_map(list)
+    })
+}
+
+// Save, start over and load, the file is returned
+fn round_trip(interp: &mut Interpreter, dir: &Path) -> String {
+    interp
+        .eval_str("save \"vars.mua erall load \"vars.mua")
+        .unwrap();
+    assert!(interp.get_var("_save1").is_err());
+    fs::read_to_string(dir.join("vars.mua")).unwrap()
+}
+
+// Same with an image, the file is returned
+fn image_round_trip(interp: &mut Interpreter, dir: &Path) -> Vec<u8> {
+    interp
+        .eval_str("saveimage \"vars.img erall loadimage \"vars.img")
+        .unwrap();
+    fs::read(dir.join("vars.img")).unwrap()
+}
+
+const CLOSURES: &str = "make \"mk [[v] [return [[w] [return [[x] [return list :v list :w :x]]]]]]
+                        make \"f mk :v
+                        make \"g f :w
+                        make \"fs list :f :g";
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn values_read_back(val in value()) {
+        let dir = dir("values");
+        for engine in ENGINES {
+            let mut interp = interpreter(engine, &dir);
+            interp.set_var("v", val.clone()).unwrap();
+            round_trip(&mut interp, &dir);
+            prop_assert_eq!(interp.get_var("v").unwrap(), val.clone());
+        }
+    }
+
+    #[test]
+    fn closures_read_back(v in value(), w in value(), x in value()) {
+        let dir = dir("closures");
+        for engine in ENGINES {
+            let mut interp = interpreter(engine, &dir);
+            interp.set_var("v", v.clone()).unwrap();
+            interp.set_var("w", w.clone()).unwrap();
+            interp.eval_str(CLOSURES).unwrap();
+            let expected = list(vec![v.clone(), list(vec![w.clone(), x.clone()])]);
+
+            let saved = round_trip(&mut interp, &dir);
+            prop_assert_eq!(interp.call_function("g", vec![x.clone()]).unwrap(), expected);
+
+            // Same values, same file
+            prop_assert_eq!(round_trip(&mut interp, &dir), saved);
+        }
+    }
+
+    #[test]
+    fn images_read_back(v in value(), w in value(), x in value()) {
+        let dir = dir("images");
+        for engine in ENGINES {
+            let mut interp = interpreter(engine, &dir);
+            interp.set_var("v", v.clone()).unwrap();
+            interp.set_var("w", w.clone()).unwrap();
+            interp.eval_str(CLOSURES).unwrap();
+            let expected = list(vec![v.clone(), list(vec![w.clone(), x.clone()])]);
+
+            let saved = image_round_trip(&mut interp, &dir);
+            prop_assert_eq!(interp.get_var("v").unwrap(), v.clone());
+            prop_assert_eq!(interp.call_function("g", vec![x.clone()]).unwrap(), expected);
+            prop_assert_eq!(image_round_trip(&mut interp, &dir), saved);
+        }
+    }
+}
+
+#[test]
+fn images_keep_shared_tables() {
+    let dir = dir("shared");
+    for engine in ENGINES {
+        let mut interp = interpreter(engine, &dir);
+        // f and g close over the same call of mk, which holds them too
+        interp
+            .eval_str(
+                "make \"mk [[n] [make \"f [[] [return :n]] make \"g [[] [return f]] return :g]]
+                 make \"g mk 7",
+            )
+            .unwrap();
+        let saved = image_round_trip(&mut interp, &dir);
+        assert_eq!(interp.eval_str("g").unwrap(), ValType::Num(7.0.into()));
+        assert_eq!(image_round_trip(&mut interp, &dir), saved);
+    }
+}
+
+#[test]
+fn comments_in_functions_are_saved() {
+    let dir = dir("comments");
+    for engine in ENGINES {
+        let mut interp = interpreter(engine, &dir);
+        interp
+            .eval_str(
+                "make \"sq [[x] [
+                   // the square
+                   return mul :x :x // done
+                 ]]
+                 make \"l [1 // one
+                   2]",
+            )
+            .unwrap();
+        let saved = round_trip(&mut interp, &dir);
+        assert!(saved.contains("make \"sq [[x] [// the square\nreturn mul :x :x // done\n]]"));
+        assert!(saved.contains("make \"l [1 2]"));
+        assert_eq!(interp.eval_str("sq 3").unwrap(), ValType::Num(9.0.into()));
+
+        // An image keeps them too
+        image_round_trip(&mut interp, &dir);
+        assert_eq!(round_trip(&mut interp, &dir), saved);
+        assert_eq!(interp.eval_str("sq 3").unwrap(), ValType::Num(9.0.into()));
+    }
+}
+
+// A word starting with // in a list can't be written in a literal
+#[test]
+fn comment_like_words_are_saved() {
+    let dir = dir("slashes");
+    for engine in ENGINES {
+        let mut interp = interpreter(engine, &dir);
+        interp
+            .eval_str("make \"l list \"//a \"b make \"m butfirst list \"x :l")
+            .unwrap();
+        let saved = round_trip(&mut interp, &dir);
+        assert!(!saved.contains("[//a"), "{}", saved);
+        let l = list(vec![ValType::Str("//a".into()), ValType::Str("b".into())]);
+        assert_eq!(interp.get_var("l").unwrap(), l);
+        assert_eq!(interp.get_var("m").unwrap(), list(vec![l]));
+    }
+}
+
+#[test]
+fn bad_images_change_nothing() {
+    let dir = dir("bad");
+    let mut interp = interpreter(Engine::Tree, &dir);
+    interp.eval_str("make \"x 1 saveimage \"vars.img").unwrap();
+    let image = fs::read(dir.join("vars.img")).unwrap();
+    interp.eval_str("make \"x 2").unwrap();
+
+    let mut newer = image.clone();
+    newer[8] += 1;
+    let bad = [
+        b"make \"x 1".to_vec(),
+        image[..image.len() - 1].to_vec(),
+        newer,
+    ];
+    for bytes in bad {
+        fs::write(dir.join("bad.img"), bytes).unwrap();
+        let err = interp.eval_str("loadimage \"bad.img").unwrap_err();
+        assert_eq!(err.kind, ErrorKind::IoError);
+        assert_eq!(interp.get_var("x").unwrap(), ValType::Num(2.0.into()));
+    }
+    let err = interp.eval_str("loadimage \"bad.img").unwrap_err();
+    assert!(err.msg.contains("version 3"), "{}", err.msg);
+
+    // Lists in lists as far as the file goes
+    let mut deep = image[..12].to_vec();
+    deep.extend(1u64.to_le_bytes());
+    deep.extend(1u64.to_le_bytes());
+    deep.push(b'x');
+    for _ in 0..1_000_000 {
+        deep.push(3);
+        deep.extend(1u64.to_le_bytes());
+    }
+    fs::write(dir.join("bad.img"), deep).unwrap();
+    let err = interp.eval_str("loadimage \"bad.img").unwrap_err();
+    assert_eq!(err.kind, ErrorKind::IoError);
+    assert!(err.msg.contains("nests too deep"), "{}", err.msg);
+
+    interp
+        .load_image(dir.join("vars.img").to_str().unwrap())
+        .unwrap();
+    assert_eq!(interp.get_var("x").unwrap(), ValType::Num(1.0.into()));
+}